@@ -0,0 +1,235 @@
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use crate::config::CloudSqlConfig;
+use crate::runner;
+use crate::util;
+use crate::FigError;
+
+const PROXY_V2: &str = "cloud-sql-proxy";
+const PROXY_V1: &str = "cloud_sql_proxy";
+/// The port in the name of the proxy's socket, `.s.PGSQL.5432`.
+pub const SOCKET_PORT: u16 = 5432;
+
+/// The Cloud SQL Auth Proxy that is installed. v2 renamed the binary and
+/// replaced `-instances X=tcp:PORT` with flags and the instance as argument.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Proxy {
+    V1,
+    V2,
+}
+
+impl Proxy {
+    /// The installed proxy, v2 if both are.
+    pub fn detect() -> Option<Proxy> {
+        if util::find_in_path(PROXY_V2).is_some() {
+            Some(Proxy::V2)
+        } else if util::find_in_path(PROXY_V1).is_some() {
+            Some(Proxy::V1)
+        } else {
+            None
+        }
+    }
+
+    pub fn binary(self) -> &'static str {
+        match self {
+            Proxy::V1 => PROXY_V1,
+            Proxy::V2 => PROXY_V2,
+        }
+    }
+
+    pub fn version_arg(self) -> &'static str {
+        match self {
+            Proxy::V1 => "-version",
+            Proxy::V2 => "--version",
+        }
+    }
+}
+
+/// The command that serves `config` on `port` of localhost, or on a unix
+/// socket in its `unix_socket` directory.
+pub fn proxy_cmd(config: &CloudSqlConfig, port: u16) -> crate::Result<Command> {
+    let proxy = Proxy::detect().ok_or_else(|| {
+        FigError::DoctorError(format!(
+            "neither {} (v2) nor {} (v1) of the Cloud SQL Auth Proxy is installed",
+            PROXY_V2, PROXY_V1
+        ))
+    })?;
+    let mut cmd = Command::new(proxy.binary());
+    cmd.args(proxy_args(proxy, config, port)?);
+
+    Ok(cmd)
+}
+
+fn proxy_args(proxy: Proxy, config: &CloudSqlConfig, port: u16) -> crate::Result<Vec<String>> {
+    let mut args = vec![];
+
+    match proxy {
+        Proxy::V2 => {
+            match &config.unix_socket {
+                Some(dir) => args.extend(vec!["--unix-socket".to_owned(), dir.clone()]),
+                None => args.extend(vec!["--port".to_owned(), port.to_string()]),
+            };
+            if config.private_ip {
+                args.push("--private-ip".to_owned());
+            }
+            if config.auto_iam_authn {
+                args.push("--auto-iam-authn".to_owned());
+            }
+            if let Some(account) = &config.impersonate_service_account {
+                args.extend(vec![
+                    "--impersonate-service-account".to_owned(),
+                    account.clone(),
+                ]);
+            }
+            args.push(config.instance.clone());
+        }
+        Proxy::V1 => {
+            if config.impersonate_service_account.is_some() {
+                return Err(FigError::ConfigError(format!(
+                    "impersonate_service_account needs {} (v2), only {} (v1) is installed",
+                    PROXY_V2, PROXY_V1
+                )));
+            }
+            match &config.unix_socket {
+                Some(dir) => args.extend(vec![
+                    "-dir".to_owned(),
+                    dir.clone(),
+                    "-instances".to_owned(),
+                    config.instance.clone(),
+                ]),
+                None => args.extend(vec![
+                    "-instances".to_owned(),
+                    format!("{}=tcp:{}", config.instance, port),
+                ]),
+            };
+            if config.private_ip {
+                args.push("-ip_address_types=PRIVATE".to_owned());
+            }
+            if config.auto_iam_authn {
+                args.push("-enable_iam_login".to_owned());
+            }
+        }
+    }
+
+    Ok(args)
+}
+
+/// The directory psql connects to when the proxy listens on a unix socket,
+/// both versions put the socket in a directory named after the instance.
+pub fn socket_dir(config: &CloudSqlConfig) -> Option<PathBuf> {
+    config
+        .unix_socket
+        .as_ref()
+        .map(|dir| Path::new(dir).join(&config.instance))
+}
+
+/// A token to log in with as the active gcloud account, in place of a
+/// password.
+pub fn login_token() -> crate::Result<String> {
+    let mut cmd = Command::new("gcloud");
+    cmd.args(vec!["sql", "generate-login-token"]);
+
+    Ok(runner::capture_command(&mut cmd)?.trim().to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn instance() -> CloudSqlConfig {
+        CloudSqlConfig {
+            instance: "figure:us-east1:main".to_owned(),
+            private_ip: false,
+            auto_iam_authn: false,
+            impersonate_service_account: None,
+            unix_socket: None,
+        }
+    }
+
+    fn with_everything() -> CloudSqlConfig {
+        CloudSqlConfig {
+            private_ip: true,
+            auto_iam_authn: true,
+            unix_socket: Some("/tmp/cloudsql".to_owned()),
+            ..instance()
+        }
+    }
+
+    #[test]
+    fn v2_serves_the_instance_on_a_port() {
+        let args = proxy_args(Proxy::V2, &instance(), 5433).unwrap();
+
+        assert_eq!(args, ["--port", "5433", "figure:us-east1:main"]);
+    }
+
+    #[test]
+    fn v2_serves_the_instance_on_a_unix_socket() {
+        let config = CloudSqlConfig {
+            impersonate_service_account: Some("db@figure.iam.gserviceaccount.com".to_owned()),
+            ..with_everything()
+        };
+        let args = proxy_args(Proxy::V2, &config, 5433).unwrap();
+
+        assert_eq!(
+            args,
+            [
+                "--unix-socket",
+                "/tmp/cloudsql",
+                "--private-ip",
+                "--auto-iam-authn",
+                "--impersonate-service-account",
+                "db@figure.iam.gserviceaccount.com",
+                "figure:us-east1:main",
+            ]
+        );
+    }
+
+    #[test]
+    fn v1_serves_the_instance_on_a_port() {
+        let args = proxy_args(Proxy::V1, &instance(), 5433).unwrap();
+
+        assert_eq!(args, ["-instances", "figure:us-east1:main=tcp:5433"]);
+    }
+
+    #[test]
+    fn v1_serves_the_instance_on_a_unix_socket() {
+        let args = proxy_args(Proxy::V1, &with_everything(), 5433).unwrap();
+
+        assert_eq!(
+            args,
+            [
+                "-dir",
+                "/tmp/cloudsql",
+                "-instances",
+                "figure:us-east1:main",
+                "-ip_address_types=PRIVATE",
+                "-enable_iam_login",
+            ]
+        );
+    }
+
+    #[test]
+    fn v1_cannot_impersonate_a_service_account() {
+        let config = CloudSqlConfig {
+            impersonate_service_account: Some("db@figure.iam.gserviceaccount.com".to_owned()),
+            ..instance()
+        };
+
+        match proxy_args(Proxy::V1, &config, 5433) {
+            Err(FigError::ConfigError(message)) => {
+                assert!(message.contains("impersonate_service_account"))
+            }
+            result => panic!("unexpected {:?}", result),
+        }
+    }
+
+    #[test]
+    fn socket_dir_is_named_after_the_instance() {
+        assert_eq!(socket_dir(&instance()), None);
+        assert_eq!(
+            socket_dir(&with_everything()),
+            Some(PathBuf::from("/tmp/cloudsql/figure:us-east1:main"))
+        );
+    }
+}
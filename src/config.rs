@@ -1,33 +1,207 @@
-use crate::FigError::EnvError;
-use serde::Deserialize;
+use crate::k8s::PodTarget;
+use crate::secret;
+use crate::util::{self, ForwardingInfo, Remote};
+use crate::FigError::{ConfigError, ConfigNotFound, EnvError};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::fs;
+use std::io;
 use std::path::Path;
 
 #[derive(Deserialize, Debug)]
 pub struct Config {
     pub port_forward: Option<PortForwardConfig>,
 
-    pub postgres_local: Option<PostgresConfig>,
-    pub postgres_test: Option<PostgresConfig>,
-    pub postgres_prod: Option<PostgresConfig>,
+    /// Environments by name, e.g. `[postgres.staging]`.
+    #[serde(default)]
+    pub postgres: BTreeMap<String, PostgresConfig>,
+    #[serde(default)]
+    pub exec: BTreeMap<String, ExecConfig>,
+    #[serde(default)]
+    pub logs: BTreeMap<String, LogConfig>,
+
+    // `[postgres_local]` style blocks from before environments could be
+    // named, folded into the maps above by `get_config`
+    postgres_local: Option<PostgresConfig>,
+    postgres_test: Option<PostgresConfig>,
+    postgres_prod: Option<PostgresConfig>,
+
+    exec_local: Option<ExecConfig>,
+    exec_test: Option<ExecConfig>,
+    exec_prod: Option<ExecConfig>,
+
+    logs_local: Option<LogConfig>,
+    logs_test: Option<LogConfig>,
+    logs_prod: Option<LogConfig>,
 }
 
-#[derive(Deserialize, Debug)]
+impl Config {
+    /// Moves the legacy `<section>_local/test/prod` blocks into their named
+    /// environment, unless the same environment is also configured by name.
+    fn fold_legacy_blocks(&mut self) {
+        fn fold<T>(envs: &mut BTreeMap<String, T>, legacy: [(&str, Option<T>); 3]) {
+            for (env, block) in legacy {
+                if let Some(block) = block {
+                    envs.entry(env.to_owned()).or_insert(block);
+                }
+            }
+        }
+
+        fold(
+            &mut self.postgres,
+            [
+                (crate::LOCAL, self.postgres_local.take()),
+                (crate::TEST, self.postgres_test.take()),
+                (crate::PRODUCTION, self.postgres_prod.take()),
+            ],
+        );
+        fold(
+            &mut self.exec,
+            [
+                (crate::LOCAL, self.exec_local.take()),
+                (crate::TEST, self.exec_test.take()),
+                (crate::PRODUCTION, self.exec_prod.take()),
+            ],
+        );
+        fold(
+            &mut self.logs,
+            [
+                (crate::LOCAL, self.logs_local.take()),
+                (crate::TEST, self.logs_test.take()),
+                (crate::PRODUCTION, self.logs_prod.take()),
+            ],
+        );
+    }
+
+    /// The `[postgres.<env>]` block with its secret references resolved.
+    pub fn postgres(&self, env: Option<&str>) -> crate::Result<PostgresConfig> {
+        environment("postgres", &self.postgres, env)
+    }
+
+    pub fn exec(&self, env: Option<&str>) -> crate::Result<ExecConfig> {
+        environment("exec", &self.exec, env)
+    }
+
+    pub fn logs(&self, env: Option<&str>) -> crate::Result<LogConfig> {
+        environment("logs", &self.logs, env)
+    }
+
+    pub fn port_forward(&self) -> crate::Result<Option<PortForwardConfig>> {
+        self.port_forward.as_ref().map(secret::resolve).transpose()
+    }
+}
+
+fn environment<T: Serialize + DeserializeOwned>(
+    section: &str,
+    envs: &BTreeMap<String, T>,
+    env: Option<&str>,
+) -> crate::Result<T> {
+    let env = env.ok_or_else(|| EnvError("environment not set".to_owned()))?;
+
+    let block = envs.get(env).ok_or_else(|| {
+        let available = envs.keys().cloned().collect::<Vec<_>>();
+        ConfigError(format!(
+            "no [{}.{}] block, configured environments are: {}",
+            section,
+            env,
+            if available.is_empty() {
+                "none".to_owned()
+            } else {
+                available.join(", ")
+            }
+        ))
+    })?;
+
+    secret::resolve(block)
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct KubernetesConfig {
+    pub context: String,
+    pub namespace: String,
+    pub deployment: Option<String>,
+    pub statefulset: Option<String>,
+    pub service: Option<String>,
+    pub pod: Option<String>,
+    /// A label selector, like `cnpg.io/cluster=db`.
+    pub selector: Option<String>,
+    pub container: Option<String>,
+}
+
+impl KubernetesConfig {
+    /// The pods to connect to, exactly one of `deployment`, `statefulset`,
+    /// `service`, `pod` and `selector` has to be set.
+    pub fn target(&self) -> crate::Result<PodTarget> {
+        let mut targets = vec![
+            self.deployment.clone().map(PodTarget::Deployment),
+            self.statefulset.clone().map(PodTarget::StatefulSet),
+            self.service.clone().map(PodTarget::Service),
+            self.pod.clone().map(PodTarget::Pod),
+            self.selector.clone().map(PodTarget::Selector),
+        ]
+        .into_iter()
+        .flatten();
+
+        match (targets.next(), targets.next()) {
+            (Some(target), None) => Ok(target),
+            _ => Err(ConfigError(
+                "a kubernetes block needs exactly one of deployment, statefulset, service, pod and selector"
+                    .to_owned(),
+            )),
+        }
+    }
+}
+
+/// The SSH bastion a database is reached through.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct SshConfig {
+    pub host: String,
+    pub user: Option<String>,
+    pub port: Option<u16>,
+    pub identity_file: Option<String>,
+    /// Jump hosts in front of the bastion, like `ssh -J`.
+    pub jump: Option<String>,
+}
+
+/// A Cloud SQL instance reached through the Cloud SQL Auth Proxy.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct CloudSqlConfig {
+    /// The connection name, `project:region:instance`.
+    pub instance: String,
+    #[serde(default)]
+    pub private_ip: bool,
+    /// Lets the proxy log in as the IAM user (or service account) running
+    /// it, no password needed.
+    #[serde(default)]
+    pub auto_iam_authn: bool,
+    pub impersonate_service_account: Option<String>,
+    /// A directory for the proxy's unix socket, instead of a local port.
+    pub unix_socket: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
 #[serde(rename_all = "lowercase")]
 pub enum ServerConfigType {
-    Kubernetes {
+    Kubernetes(KubernetesConfig),
+    /// A host only reachable from inside the cluster, like a Cloud SQL or
+    /// RDS private IP, forwarded to through a relay pod.
+    KubernetesRelay {
         context: String,
         namespace: String,
-        deployment: String,
-        container: Option<String>,
-    },
-    GCloudProxy {
-        instance: String,
+        remote_host: String,
+        remote_port: u16,
+        #[serde(default)]
+        relay_pod: RelayPodConfig,
     },
+    GCloudProxy(CloudSqlConfig),
+    /// A tunnel through an SSH bastion, `host` and `port` of the Postgres
+    /// config are the database as the bastion sees it.
+    Ssh(SshConfig),
     Direct,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct PostgresConfig {
     #[serde(rename = "type")]
     pub _type: ServerConfigType,
@@ -35,11 +209,90 @@ pub struct PostgresConfig {
     pub port: Option<u16>,
     pub user: String,
     pub password: Option<String>,
+    /// Where to get the password (and possibly the user) from instead of
+    /// `password`.
+    pub password_source: Option<PasswordSource>,
     pub database: String,
     pub schema: Option<String>,
+    /// Protected environments ask for their name to be typed before
+    /// connecting and log every session to the audit log. Defaults to true
+    /// for `prod`.
+    pub protected: Option<bool>,
+    /// Opens psql sessions with `default_transaction_read_only` turned on.
+    #[serde(default)]
+    pub read_only: bool,
+    pub prompt_color: Option<PromptColor>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(rename_all = "lowercase")]
+pub enum PasswordSource {
+    Vault(VaultSource),
+    /// A login token of the active gcloud account, for Cloud SQL IAM
+    /// database authentication. It is valid for an hour.
+    Iam,
+}
+
+/// A Vault secret holding the database password. With `role`, `path` is the
+/// mount of a database secrets engine and `{path}/creds/{role}` is read for
+/// a dynamic user and password. Without it, `field` (default `password`) of
+/// the KV secret at `path` is used.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct VaultSource {
+    pub path: String,
+    pub field: Option<String>,
+    pub role: Option<String>,
+    /// Defaults to `VAULT_ADDR`.
+    pub address: Option<String>,
+}
+
+/// The user and password to connect with, once any `password_source` has
+/// been resolved.
+#[derive(Debug, Clone)]
+pub struct Credentials {
+    pub user: String,
+    pub password: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum PromptColor {
+    Red,
+    Green,
+    Yellow,
+    Blue,
+    Magenta,
+    Cyan,
+}
+
+impl PromptColor {
+    pub fn ansi_code(self) -> u8 {
+        match self {
+            PromptColor::Red => 31,
+            PromptColor::Green => 32,
+            PromptColor::Yellow => 33,
+            PromptColor::Blue => 34,
+            PromptColor::Magenta => 35,
+            PromptColor::Cyan => 36,
+        }
+    }
 }
 
 impl PostgresConfig {
+    pub fn is_protected(&self, env: &str) -> bool {
+        self.protected.unwrap_or(env == crate::PRODUCTION)
+    }
+
+    /// Red for protected environments, green for everything else, unless
+    /// configured otherwise.
+    pub fn prompt_color(&self, env: &str) -> PromptColor {
+        self.prompt_color.unwrap_or(if self.is_protected(env) {
+            PromptColor::Red
+        } else {
+            PromptColor::Green
+        })
+    }
+
     pub fn host(&self) -> String {
         self.host.clone().unwrap_or_else(|| "localhost".to_owned())
     }
@@ -53,48 +306,222 @@ impl PostgresConfig {
     }
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Serialize, Debug)]
 pub struct PortForwardConfig {
     pub context: String,
     pub namespace: Option<String>,
+    /// Named forwardings, e.g. `[port_forward.targets.redis]`.
+    #[serde(default)]
+    pub targets: BTreeMap<String, PortForwardTarget>,
+    #[serde(default)]
+    pub relay_pod: RelayPodConfig,
+}
+
+/// The pod that relays forwardings to hosts, `[port_forward.relay_pod]`.
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct RelayPodConfig {
+    /// Defaults to `alpine/socat`, any image with `sh` and `socat` works.
+    pub image: Option<String>,
+    pub image_pull_secret: Option<String>,
+    /// Added to `linkerd.io/inject = "disabled"`, which can be overridden.
+    #[serde(default)]
+    pub annotations: BTreeMap<String, String>,
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+    #[serde(default)]
+    pub node_selector: BTreeMap<String, String>,
+    #[serde(default)]
+    pub tolerations: Vec<Toleration>,
+    pub resources: Option<Resources>,
+    /// Seconds after which the pod is stopped in case figcli couldn't
+    /// delete it, 8 hours by default.
+    pub deadline: Option<u64>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Toleration {
+    pub key: Option<String>,
+    pub operator: Option<String>,
+    pub value: Option<String>,
+    pub effect: Option<String>,
+    pub toleration_seconds: Option<i64>,
 }
 
-// #[derive(Deserialize, Debug)]
-// pub struct K8sConfig {
-//     deployment: String,
-// }
+/// Resource requests and limits, e.g. `limits = { cpu = "100m", memory = "32Mi" }`.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Resources {
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub requests: BTreeMap<String, String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub limits: BTreeMap<String, String>,
+}
+
+/// A forwarding to `remote_host` through the relay pod, or straight to a
+/// Kubernetes `service`, `pod` or `deployment`. Without `local_port` a free
+/// port is picked.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct PortForwardTarget {
+    pub remote_host: Option<String>,
+    pub service: Option<String>,
+    pub pod: Option<String>,
+    pub deployment: Option<String>,
+    pub remote_port: u16,
+    pub local_port: Option<u16>,
+}
 
-// #[derive(Deserialize, Debug)]
-// pub struct ExecConfig {
-//     cmd: String,
-// }
+impl PortForwardTarget {
+    pub fn forwarding(&self, name: &str) -> crate::Result<ForwardingInfo> {
+        let resource = |kind: &str, name: &Option<String>| {
+            name.as_ref()
+                .map(|name| Remote::Resource(format!("{}/{}", kind, name)))
+        };
+        let mut remotes = vec![
+            self.remote_host.clone().map(Remote::Host),
+            resource("svc", &self.service),
+            resource("pod", &self.pod),
+            resource("deploy", &self.deployment),
+        ]
+        .into_iter()
+        .flatten();
+        let remote = match (remotes.next(), remotes.next()) {
+            (Some(remote), None) => remote,
+            _ => {
+                return Err(ConfigError(format!(
+                    "port_forward target {} needs exactly one of remote_host, service, pod and deployment",
+                    name
+                )))
+            }
+        };
+        let local_port = match self.local_port {
+            Some(port) => port,
+            None => util::find_available_port()?,
+        };
 
-// #[derive(Deserialize, Debug)]
-// pub struct LogConfig {
-//     follow: bool,
-// }
+        Ok(ForwardingInfo {
+            local_port,
+            remote,
+            remote_port: self.remote_port,
+        })
+    }
+}
 
-#[derive(Deserialize, Eq, Hash, PartialEq)]
-pub enum EnvironmentType {
-    #[serde(rename = "local")]
-    Local,
-    #[serde(rename = "test")]
-    Test,
-    #[serde(rename = "prod")]
-    Production,
+#[derive(Deserialize, Serialize, Debug)]
+pub struct ExecConfig {
+    #[serde(flatten)]
+    pub kubernetes: KubernetesConfig,
+    pub cmd: Option<String>,
 }
 
-pub fn environment_type(env: Option<&str>) -> crate::Result<EnvironmentType> {
-    match env {
-        Some(crate::LOCAL) => Ok(EnvironmentType::Local),
-        Some(crate::TEST) => Ok(EnvironmentType::Test),
-        Some(crate::PRODUCTION) => Ok(EnvironmentType::Production),
-        _ => Err(EnvError("environment not set".to_owned())),
+impl ExecConfig {
+    /// The configured `cmd` split into words like a POSIX shell would, so
+    /// quoted arguments stay together (`sh -c 'cd /app && exec bash'`).
+    pub fn cmd(&self) -> crate::Result<Vec<String>> {
+        let cmd = self.cmd.as_deref().unwrap_or("/bin/sh");
+        shlex::split(cmd)
+            .filter(|words| !words.is_empty())
+            .ok_or_else(|| ConfigError(format!("can't parse exec cmd `{}`", cmd)))
     }
 }
 
+#[derive(Deserialize, Serialize, Debug)]
+pub struct LogConfig {
+    #[serde(flatten)]
+    pub kubernetes: KubernetesConfig,
+    #[serde(default)]
+    pub follow: bool,
+    pub since: Option<String>,
+}
+
 pub fn get_config<P: AsRef<Path>>(path: P) -> crate::Result<Config> {
-    let toml_string = fs::read_to_string(path)?;
+    let toml_string = fs::read_to_string(path.as_ref()).map_err(|e| match e.kind() {
+        io::ErrorKind::NotFound => ConfigNotFound(path.as_ref().to_path_buf()),
+        _ => e.into(),
+    })?;
+
+    let mut config: Config = toml::from_str(&toml_string)?;
+    config.fold_legacy_blocks();
+
+    Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(contents: &str) -> Config {
+        let mut config: Config = toml::from_str(contents).unwrap();
+        config.fold_legacy_blocks();
+        config
+    }
+
+    #[test]
+    fn legacy_blocks_become_named_environments() {
+        let config = parse(
+            r#"
+            [postgres_test]
+            type = "direct"
+            user = "legacy"
+            database = "app"
+
+            [exec_local]
+            context = "kind"
+            namespace = "app"
+            deployment = "api"
 
-    toml::from_str(&toml_string).map_err(From::from)
+            [logs_prod]
+            context = "gke"
+            namespace = "app"
+            deployment = "api"
+            "#,
+        );
+
+        assert_eq!(config.postgres["test"].user, "legacy");
+        assert_eq!(config.exec["local"].kubernetes.context, "kind");
+        assert_eq!(config.logs["prod"].kubernetes.context, "gke");
+        assert_eq!(
+            config.postgres.len() + config.exec.len() + config.logs.len(),
+            3
+        );
+    }
+
+    #[test]
+    fn named_blocks_win_over_legacy_blocks() {
+        let config = parse(
+            r#"
+            [postgres_test]
+            type = "direct"
+            user = "legacy"
+            database = "app"
+
+            [postgres.test]
+            type = "direct"
+            user = "named"
+            database = "app"
+
+            [exec_test]
+            context = "legacy"
+            namespace = "app"
+            deployment = "api"
+
+            [exec.test]
+            context = "named"
+            namespace = "app"
+            deployment = "api"
+
+            [logs.local]
+            context = "named"
+            namespace = "app"
+            deployment = "api"
+
+            [logs_local]
+            context = "legacy"
+            namespace = "app"
+            deployment = "api"
+            "#,
+        );
+
+        assert_eq!(config.postgres["test"].user, "named");
+        assert_eq!(config.exec["test"].kubernetes.context, "named");
+        assert_eq!(config.logs["local"].kubernetes.context, "named");
+    }
 }
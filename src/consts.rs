@@ -2,9 +2,11 @@ pub const CHECK: &str = "check";
 pub const CONFIG: &str = "config";
 pub const DOCTOR: &str = "doctor";
 pub const EDIT: &str = "edit";
+pub const EXEC: &str = "exec";
 pub const INIT: &str = "init";
 pub const KONG_API_KEY: &str = "kong-api-key";
 pub const LIST: &str = "list";
+pub const LOGS: &str = "logs";
 pub const PATH: &str = "path";
 pub const PORT_FORWARD: &str = "port-forward";
 pub const POSTGRES_CLI: &str = "psql";
@@ -14,6 +16,8 @@ pub const TEST: &str = "test";
 pub const LOCAL: &str = "local";
 pub const PRODUCTION: &str = "prod";
 pub const FIG_CONFIG_DIR: &str = "fig";
+pub const AUDIT_LOG: &str = "audit.log";
+pub const VAULT_CACHE_DIR: &str = "vault-cache";
 
 pub const GREEN_CHECK_ICON: &str = "\u{2705}";
 pub const RED_X_ICON: &str = "\u{274C}";
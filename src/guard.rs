@@ -0,0 +1,137 @@
+use std::env;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::config::PromptColor;
+use crate::FigError;
+
+/// Makes the user type the name of the protected environment `env` before
+/// continuing. `confirmed` is the value of `--confirm`, for non-interactive use.
+pub fn confirm(env: &str, confirmed: Option<&str>) -> crate::Result<()> {
+    let answer = match confirmed {
+        Some(answer) => answer.to_owned(),
+        None => {
+            print!(
+                "\n{} is a protected environment. Type its name to continue: ",
+                env
+            );
+            io::stdout().flush()?;
+
+            let mut answer = String::new();
+            io::stdin().lock().read_line(&mut answer)?;
+            answer.trim().to_owned()
+        }
+    };
+
+    if answer == env {
+        Ok(())
+    } else {
+        Err(FigError::ConfirmationError(format!(
+            "\"{}\" does not match the environment name {}",
+            answer, env
+        )))
+    }
+}
+
+/// A psql `PROMPT1` that shows the environment, e.g. `[prod] db=>`.
+pub fn psql_prompt(env: &str, color: PromptColor) -> String {
+    format!(
+        "%[%033[1;{}m%][{}]%[%033[0m%] %/%R%# ",
+        color.ansi_code(),
+        env
+    )
+}
+
+/// One line of the audit log, written for every session against a protected
+/// environment.
+pub struct AuditEntry<'a> {
+    pub config: &'a str,
+    pub env: &'a str,
+    pub mode: &'a str,
+    pub user: &'a str,
+    pub database: &'a str,
+    pub read_only: bool,
+}
+
+/// Appends `entry` to the audit log at `path`, which is only readable by the
+/// current user.
+pub fn audit<P: AsRef<Path>>(path: P, entry: &AuditEntry) -> crate::Result<()> {
+    let line = format!(
+        "{} os_user={} config={} env={} mode={} target={}@{} read_only={} cwd={}\n",
+        utc_timestamp(SystemTime::now()),
+        env::var("USER").unwrap_or_else(|_| "unknown".to_owned()),
+        entry.config,
+        entry.env,
+        entry.mode,
+        entry.user,
+        entry.database,
+        entry.read_only,
+        env::current_dir()?.display()
+    );
+
+    let mut file = fs::OpenOptions::new()
+        .append(true)
+        .create(true)
+        .mode(0o600)
+        .open(path)?;
+    file.write_all(line.as_bytes())?;
+
+    Ok(())
+}
+
+/// Formats `time` as an RFC 3339 UTC timestamp, e.g. `2022-11-03T14:05:09Z`.
+fn utc_timestamp(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0);
+    let (days, secs_of_day) = (secs.div_euclid(86_400), secs.rem_euclid(86_400));
+
+    // days since the epoch to a civil date, from
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        secs_of_day / 3_600,
+        secs_of_day % 3_600 / 60,
+        secs_of_day % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn at(secs: u64) -> String {
+        utc_timestamp(UNIX_EPOCH + Duration::from_secs(secs))
+    }
+
+    #[test]
+    fn utc_timestamp_formats_rfc3339() {
+        assert_eq!(at(0), "1970-01-01T00:00:00Z");
+        assert_eq!(at(1_667_484_309), "2022-11-03T14:05:09Z");
+    }
+
+    #[test]
+    fn utc_timestamp_handles_leap_days() {
+        assert_eq!(at(951_782_400), "2000-02-29T00:00:00Z");
+        assert_eq!(at(951_782_400 + 86_400), "2000-03-01T00:00:00Z");
+        assert_eq!(at(1_704_067_199), "2023-12-31T23:59:59Z");
+    }
+}
@@ -0,0 +1,428 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::process::Command;
+
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::{json, Map};
+
+use crate::config::{KubernetesConfig, RelayPodConfig};
+use crate::runner;
+use crate::FigError;
+
+const RELAY_POD_IMAGE: &str = "alpine/socat";
+const RELAY_POD_DEADLINE: u64 = 8 * 60 * 60;
+
+/// Labels operators put on the primary instance of a Postgres cluster:
+/// CloudNativePG, the Zalando operator (Spilo), Crunchy Data and the generic
+/// `role` many charts use.
+const PRIMARY_LABELS: &[(&str, &str)] = &[
+    ("cnpg.io/instanceRole", "primary"),
+    ("role", "primary"),
+    ("spilo-role", "master"),
+    ("postgres-operator.crunchydata.com/role", "master"),
+];
+
+/// What a Kubernetes environment connects to: the pods of a workload or a
+/// service, one pod, or the pods matching a label selector.
+#[derive(Clone, Debug)]
+pub enum PodTarget {
+    Deployment(String),
+    StatefulSet(String),
+    Service(String),
+    Pod(String),
+    Selector(String),
+}
+
+impl fmt::Display for PodTarget {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PodTarget::Deployment(name) => write!(f, "deployment/{}", name),
+            PodTarget::StatefulSet(name) => write!(f, "statefulset/{}", name),
+            PodTarget::Service(name) => write!(f, "service/{}", name),
+            PodTarget::Pod(name) => write!(f, "pod/{}", name),
+            PodTarget::Selector(selector) => write!(f, "pods matching {}", selector),
+        }
+    }
+}
+
+/// Reads the objects of one namespace, through kubectl or the API.
+pub trait Api {
+    /// The object `name` of `kind` (singular, like `deployment`).
+    fn get<T: DeserializeOwned>(&self, kind: &str, name: &str) -> crate::Result<T>;
+
+    fn pods(&self, selector: &str) -> crate::Result<Vec<Pod>>;
+}
+
+#[derive(Deserialize)]
+pub struct List<T> {
+    pub items: Vec<T>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Metadata {
+    name: String,
+    #[serde(default)]
+    labels: BTreeMap<String, String>,
+    deletion_timestamp: Option<String>,
+}
+
+/// A deployment or a statefulset.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Workload {
+    spec: WorkloadSpec,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct WorkloadSpec {
+    selector: LabelSelector,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct LabelSelector {
+    #[serde(default)]
+    match_labels: BTreeMap<String, String>,
+}
+
+#[derive(Deserialize)]
+struct Service {
+    spec: ServiceSpec,
+}
+
+#[derive(Deserialize)]
+struct ServiceSpec {
+    #[serde(default)]
+    selector: BTreeMap<String, String>,
+}
+
+#[derive(Deserialize)]
+pub struct Pod {
+    metadata: Metadata,
+    #[serde(default)]
+    status: PodStatus,
+}
+
+#[derive(Deserialize, Default)]
+struct PodStatus {
+    #[serde(default)]
+    conditions: Vec<PodCondition>,
+}
+
+#[derive(Deserialize)]
+struct PodCondition {
+    #[serde(rename = "type")]
+    _type: String,
+    status: String,
+}
+
+impl Pod {
+    fn is_ready(&self) -> bool {
+        self.metadata.deletion_timestamp.is_none()
+            && self
+                .status
+                .conditions
+                .iter()
+                .any(|c| c._type == "Ready" && c.status == "True")
+    }
+
+    fn is_primary(&self) -> bool {
+        PRIMARY_LABELS
+            .iter()
+            .any(|(key, value)| self.metadata.labels.get(*key).map(String::as_str) == Some(value))
+    }
+
+    /// Whether the pod is labelled with its role, primary or not.
+    fn has_role(&self) -> bool {
+        PRIMARY_LABELS
+            .iter()
+            .any(|(key, _)| self.metadata.labels.contains_key(*key))
+    }
+}
+
+/// The ready pod of `target`'s `pods` (sorted by name) to connect to, the
+/// first one.
+/// Workloads and selectors whose pods are labelled with their role only
+/// resolve to the primary: without a ready one (e.g. during a failover) it's
+/// an error rather than a session on a read-only replica. Services and single
+/// pods are taken as they are, they pick their pods themselves.
+fn first_ready(target: &PodTarget, pods: Vec<Pod>) -> crate::Result<String> {
+    let primary_only = !matches!(target, PodTarget::Service(_) | PodTarget::Pod(_))
+        && pods.iter().any(Pod::has_role);
+
+    let mut ready = pods.into_iter().filter(Pod::is_ready);
+    let pod = if primary_only {
+        ready.find(Pod::is_primary)
+    } else {
+        ready.next()
+    };
+
+    pod.map(|p| p.metadata.name).ok_or_else(|| {
+        FigError::KubernetesError(format!(
+            "found no ready {} for {}",
+            if primary_only { "primary pod" } else { "pod" },
+            target
+        ))
+    })
+}
+
+/// `labels` as a label selector.
+fn label_selector(labels: &BTreeMap<String, String>, target: &PodTarget) -> crate::Result<String> {
+    if labels.is_empty() {
+        return Err(FigError::ConfigError(format!(
+            "{} has no label selector",
+            target
+        )));
+    }
+
+    Ok(labels
+        .iter()
+        .map(|(k, v)| format!("{}={}", k, v))
+        .collect::<Vec<_>>()
+        .join(","))
+}
+
+/// The pods of `target`, sorted by name.
+fn target_pods<A: Api>(api: &A, target: &PodTarget) -> crate::Result<Vec<Pod>> {
+    let selector = match target {
+        PodTarget::Deployment(name) => {
+            let workload: Workload = api.get("deployment", name)?;
+            label_selector(&workload.spec.selector.match_labels, target)?
+        }
+        PodTarget::StatefulSet(name) => {
+            let workload: Workload = api.get("statefulset", name)?;
+            label_selector(&workload.spec.selector.match_labels, target)?
+        }
+        PodTarget::Service(name) => {
+            let service: Service = api.get("service", name)?;
+            label_selector(&service.spec.selector, target)?
+        }
+        PodTarget::Pod(name) => return Ok(vec![api.get("pod", name)?]),
+        PodTarget::Selector(selector) => selector.clone(),
+    };
+
+    let mut pods = api.pods(&selector)?;
+    pods.sort_by(|a, b| a.metadata.name.cmp(&b.metadata.name));
+
+    Ok(pods)
+}
+
+/// The names of all pods of `target` that aren't being deleted, ready or
+/// not.
+pub fn pods<A: Api>(api: &A, target: &PodTarget) -> crate::Result<Vec<String>> {
+    Ok(target_pods(api, target)?
+        .into_iter()
+        .filter(|p| p.metadata.deletion_timestamp.is_none())
+        .map(|p| p.metadata.name)
+        .collect())
+}
+
+/// Picks the ready pod of `target` to connect to, the primary if its pods
+/// are labelled with their role, see `first_ready`.
+pub fn ready_pod<A: Api>(api: &A, target: &PodTarget) -> crate::Result<String> {
+    first_ready(target, target_pods(api, target)?)
+}
+
+/// Reads objects with `kubectl get`, from the configured context and
+/// namespace.
+pub struct Kubectl<'a>(pub &'a KubernetesConfig);
+
+impl Api for Kubectl<'_> {
+    fn get<T: DeserializeOwned>(&self, kind: &str, name: &str) -> crate::Result<T> {
+        get_json(self.0, &[&format!("{}/{}", kind, name)])
+    }
+
+    fn pods(&self, selector: &str) -> crate::Result<Vec<Pod>> {
+        Ok(get_json::<List<Pod>>(self.0, &["pods", "-l", selector])?.items)
+    }
+}
+
+/// A `kubectl` command bound to the configured context and namespace.
+pub fn kubectl(config: &KubernetesConfig) -> Command {
+    let mut cmd = Command::new("kubectl");
+    cmd.args(vec![
+        "--context",
+        &config.context,
+        "--namespace",
+        &config.namespace,
+    ]);
+
+    cmd
+}
+
+fn get_json<T: DeserializeOwned>(config: &KubernetesConfig, resource: &[&str]) -> crate::Result<T> {
+    let mut cmd = kubectl(config);
+    cmd.arg("get").args(resource).args(vec!["-o", "json"]);
+
+    let output = runner::capture_command(&mut cmd)?;
+
+    serde_json::from_str(&output)
+        .map_err(|e| FigError::ParseError(format!("unexpected kubectl output: {}", e)))
+}
+
+/// The image of the relay pod `kubectl run` starts.
+pub fn relay_pod_image(config: &RelayPodConfig) -> &str {
+    config.image.as_deref().unwrap_or(RELAY_POD_IMAGE)
+}
+
+/// The `--overrides` of `kubectl run` for the relay pod `pod_name`. They are
+/// applied as a strategic merge patch (`--override-type=strategic`), which
+/// merges `containers` by name: the container (named after the pod) keeps
+/// the image and command `kubectl run` generated and only gets resources.
+/// A plain JSON merge patch would replace the whole array instead.
+pub fn relay_pod_overrides(config: &RelayPodConfig, pod_name: &str) -> String {
+    let mut annotations = BTreeMap::new();
+    annotations.insert("linkerd.io/inject".to_owned(), "disabled".to_owned());
+    annotations.extend(config.annotations.clone());
+
+    let mut metadata = Map::new();
+    metadata.insert("annotations".to_owned(), json!(annotations));
+    if !config.labels.is_empty() {
+        metadata.insert("labels".to_owned(), json!(config.labels));
+    }
+
+    let mut spec = Map::new();
+    spec.insert(
+        "activeDeadlineSeconds".to_owned(),
+        json!(config.deadline.unwrap_or(RELAY_POD_DEADLINE)),
+    );
+    if let Some(secret) = &config.image_pull_secret {
+        spec.insert("imagePullSecrets".to_owned(), json!([{ "name": secret }]));
+    }
+    if !config.node_selector.is_empty() {
+        spec.insert("nodeSelector".to_owned(), json!(config.node_selector));
+    }
+    if !config.tolerations.is_empty() {
+        let tolerations = config
+            .tolerations
+            .iter()
+            .map(|t| {
+                let fields = vec![
+                    ("key", t.key.as_ref().map(|v| json!(v))),
+                    ("operator", t.operator.as_ref().map(|v| json!(v))),
+                    ("value", t.value.as_ref().map(|v| json!(v))),
+                    ("effect", t.effect.as_ref().map(|v| json!(v))),
+                    ("tolerationSeconds", t.toleration_seconds.map(|v| json!(v))),
+                ];
+                fields
+                    .into_iter()
+                    .filter_map(|(name, value)| value.map(|value| (name.to_owned(), value)))
+                    .collect::<Map<_, _>>()
+            })
+            .collect::<Vec<_>>();
+        spec.insert("tolerations".to_owned(), json!(tolerations));
+    }
+    if let Some(resources) = &config.resources {
+        spec.insert(
+            "containers".to_owned(),
+            json!([{ "name": pod_name, "resources": resources }]),
+        );
+    }
+
+    json!({ "metadata": metadata, "spec": spec }).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+
+    /// Serves a statefulset, a service and `pods` (JSON, like the API returns
+    /// them), whatever the selector.
+    struct Stub(Vec<Value>);
+
+    impl Api for Stub {
+        fn get<T: DeserializeOwned>(&self, kind: &str, name: &str) -> crate::Result<T> {
+            let object = match kind {
+                "statefulset" => {
+                    json!({ "spec": { "selector": { "matchLabels": { "app": name } } } })
+                }
+                "service" => json!({ "spec": { "selector": { "app": name } } }),
+                _ => self
+                    .0
+                    .iter()
+                    .find(|pod| pod["metadata"]["name"] == name)
+                    .cloned()
+                    .unwrap(),
+            };
+            Ok(serde_json::from_value(object).unwrap())
+        }
+
+        fn pods(&self, _selector: &str) -> crate::Result<Vec<Pod>> {
+            Ok(serde_json::from_value(json!(self.0)).unwrap())
+        }
+    }
+
+    fn pod(name: &str, ready: bool, role: Option<&str>) -> Value {
+        let labels = role.map_or_else(|| json!({}), |role| json!({ "role": role }));
+        json!({
+            "metadata": { "name": name, "labels": labels },
+            "status": { "conditions": [{ "type": "Ready", "status": if ready { "True" } else { "False" } }] }
+        })
+    }
+
+    fn statefulset() -> PodTarget {
+        PodTarget::StatefulSet("pg".to_owned())
+    }
+
+    #[test]
+    fn picks_the_first_ready_pod_by_name() {
+        let api = Stub(vec![
+            pod("db-2", true, None),
+            pod("db-0", false, None),
+            pod("db-1", true, None),
+        ]);
+
+        assert_eq!(ready_pod(&api, &statefulset()).unwrap(), "db-1");
+    }
+
+    #[test]
+    fn picks_the_primary_of_labelled_pods() {
+        let api = Stub(vec![
+            pod("pg-0", true, Some("replica")),
+            pod("pg-1", true, Some("primary")),
+        ]);
+
+        assert_eq!(ready_pod(&api, &statefulset()).unwrap(), "pg-1");
+        let selector = PodTarget::Selector("cluster=pg".to_owned());
+        assert_eq!(ready_pod(&api, &selector).unwrap(), "pg-1");
+    }
+
+    #[test]
+    fn fails_without_a_ready_primary_instead_of_using_a_replica() {
+        let api = Stub(vec![
+            pod("pg-0", true, Some("replica")),
+            pod("pg-1", false, Some("primary")),
+        ]);
+
+        match ready_pod(&api, &statefulset()) {
+            Err(FigError::KubernetesError(message)) => {
+                assert_eq!(message, "found no ready primary pod for statefulset/pg")
+            }
+            other => panic!("expected a kubernetes error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn services_and_pods_are_taken_as_they_are() {
+        let api = Stub(vec![pod("pg-0", true, Some("replica"))]);
+
+        let service = PodTarget::Service("pg-ro".to_owned());
+        assert_eq!(ready_pod(&api, &service).unwrap(), "pg-0");
+        let single = PodTarget::Pod("pg-0".to_owned());
+        assert_eq!(ready_pod(&api, &single).unwrap(), "pg-0");
+    }
+
+    #[test]
+    fn skips_pods_being_deleted() {
+        let mut deleted = pod("db-0", true, None);
+        deleted["metadata"]["deletionTimestamp"] = json!("2026-01-01T00:00:00Z");
+        let api = Stub(vec![deleted, pod("db-1", true, None)]);
+
+        assert_eq!(ready_pod(&api, &statefulset()).unwrap(), "db-1");
+        assert_eq!(pods(&api, &statefulset()).unwrap(), vec!["db-1"]);
+    }
+}
@@ -0,0 +1,743 @@
+use std::convert::TryFrom;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream, ToSocketAddrs};
+use std::os::unix::io::AsRawFd;
+use std::process::Command;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use rustls::{ClientConfig, ClientConnection, RootCertStore, StreamOwned};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use tungstenite::client::IntoClientRequest;
+use tungstenite::handshake::HandshakeError;
+use tungstenite::http::HeaderValue;
+use tungstenite::{Message, WebSocket};
+
+use crate::k8s::{self, List, Pod, PodTarget};
+use crate::kubeconfig::{self, Auth, ExecConfig};
+use crate::runner;
+use crate::FigError;
+
+/// The binary port-forward protocol: every port has a data channel and an
+/// error channel, and each message starts with its channel's number.
+const PORT_FORWARD_PROTOCOL: &str = "v4.channel.k8s.io";
+const DATA_CHANNEL: u8 = 0;
+const ERROR_CHANNEL: u8 = 1;
+/// The server starts each channel with the port number, two bytes.
+const PORT_PREFIX_LEN: usize = 2;
+const EXEC_CREDENTIAL_API_VERSION: &str = "client.authentication.k8s.io/v1beta1";
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+const RETRY_INTERVAL: Duration = Duration::from_secs(1);
+const POLL_TIMEOUT_MS: i32 = 1000;
+const BUFFER_SIZE: usize = 32 * 1024;
+
+type TlsStream = StreamOwned<ClientConnection, TcpStream>;
+
+/// The connection under a port-forward websocket, whose socket `pump` polls.
+trait Socket: Read + Write {
+    fn tcp(&self) -> &TcpStream;
+}
+
+impl Socket for TlsStream {
+    fn tcp(&self) -> &TcpStream {
+        &self.sock
+    }
+}
+
+#[derive(Deserialize)]
+struct Status {
+    message: String,
+}
+
+#[derive(Deserialize)]
+struct ExecCredential {
+    status: ExecCredentialStatus,
+}
+
+#[derive(Deserialize)]
+struct ExecCredentialStatus {
+    token: Option<String>,
+}
+
+fn api_error<E: std::fmt::Display>(path: &str) -> impl FnOnce(E) -> FigError + '_ {
+    move |e| FigError::KubernetesError(format!("{}: {}", path, e))
+}
+
+/// The `message` of a `Status` response, or the response itself.
+fn status_message(status: u16, body: &str) -> String {
+    match serde_json::from_str::<Status>(body) {
+        Ok(status) => status.message,
+        Err(_) => format!("status {} {}", status, body.trim()),
+    }
+}
+
+/// A client for the parts of the Kubernetes API that `figcli psql` needs,
+/// logging in with the credentials of a kubeconfig context.
+pub struct Client {
+    /// `host:port`, with brackets around IPv6 addresses.
+    authority: String,
+    /// Where the API lives on the server, usually nothing.
+    path_prefix: String,
+    server_name: ServerName<'static>,
+    tls: Arc<ClientConfig>,
+    agent: ureq::Agent,
+    auth: Auth,
+    /// The token of the exec credential plugin, until the server rejects it.
+    exec_token: Mutex<Option<String>>,
+}
+
+impl Client {
+    /// A client for `context`. An error means the context can't be used
+    /// without kubectl, it says why.
+    pub fn new(context: &str) -> Result<Self, String> {
+        let access = kubeconfig::load(context)?;
+        let (authority, path_prefix) = match access.server["https://".len()..].split_once('/') {
+            Some((authority, path)) => (authority.to_owned(), format!("/{}", path)),
+            None => (access.server["https://".len()..].to_owned(), String::new()),
+        };
+        let authority = if authority.ends_with(']') || !authority.contains(':') {
+            format!("{}:443", authority)
+        } else {
+            authority
+        };
+        let host = authority
+            .rsplit_once(':')
+            .map(|(host, _)| host.trim_start_matches('[').trim_end_matches(']'))
+            .unwrap_or_default();
+        let server_name = ServerName::try_from(
+            access
+                .tls_server_name
+                .clone()
+                .unwrap_or_else(|| host.to_owned()),
+        )
+        .map_err(|e| format!("invalid server name: {}", e))?;
+
+        let tls = Arc::new(tls_config(&access)?);
+        let agent = ureq::AgentBuilder::new()
+            .timeout(REQUEST_TIMEOUT)
+            .tls_config(tls.clone())
+            .build();
+
+        Ok(Client {
+            authority,
+            path_prefix,
+            server_name,
+            tls,
+            agent,
+            auth: access.auth,
+            exec_token: Mutex::new(None),
+        })
+    }
+
+    /// The `Authorization` header, running the credential plugin for a new
+    /// token if there is none yet or `refresh` is set.
+    fn authorization(&self, refresh: bool) -> crate::Result<Option<String>> {
+        Ok(match &self.auth {
+            Auth::None => None,
+            Auth::Token(token) => Some(format!("Bearer {}", token)),
+            Auth::Basic { username, password } => Some(format!(
+                "Basic {}",
+                base64::encode(format!("{}:{}", username, password))
+            )),
+            Auth::Exec(exec) => {
+                let mut token = self
+                    .exec_token
+                    .lock()
+                    .map_err(|_| io::Error::other("token lock poisoned"))?;
+                if refresh || token.is_none() {
+                    *token = Some(exec_token(exec)?);
+                }
+                token.as_ref().map(|token| format!("Bearer {}", token))
+            }
+        })
+    }
+
+    /// Whether a rejected token is worth another try with a new one.
+    fn can_refresh(&self) -> bool {
+        matches!(self.auth, Auth::Exec(_))
+    }
+
+    fn get<T: DeserializeOwned>(&self, path: &str) -> crate::Result<T> {
+        let url = format!("https://{}{}{}", self.authority, self.path_prefix, path);
+        let mut refresh = false;
+
+        loop {
+            let mut request = self.agent.get(&url);
+            if let Some(authorization) = self.authorization(refresh)? {
+                request = request.set("Authorization", &authorization);
+            }
+
+            match request.call() {
+                Ok(response) => return response.into_json().map_err(api_error(path)),
+                Err(ureq::Error::Status(401, _)) if self.can_refresh() && !refresh => {
+                    refresh = true
+                }
+                Err(ureq::Error::Status(status, response)) => {
+                    let body = response.into_string().unwrap_or_default();
+                    return Err(api_error(path)(status_message(status, &body)));
+                }
+                Err(e) => return Err(api_error(path)(e)),
+            }
+        }
+    }
+
+    /// The objects of `namespace`.
+    fn namespace<'a>(&'a self, namespace: &'a str) -> Namespace<'a> {
+        Namespace {
+            client: self,
+            name: namespace,
+        }
+    }
+
+    /// Opens a port-forward stream to `port` of `pod`.
+    fn port_forward(
+        &self,
+        namespace: &str,
+        pod: &str,
+        port: u16,
+    ) -> crate::Result<WebSocket<TlsStream>> {
+        let path = format!(
+            "/api/v1/namespaces/{}/pods/{}/portforward?ports={}",
+            namespace, pod, port
+        );
+        let url = format!("wss://{}{}{}", self.authority, self.path_prefix, path);
+        let mut refresh = false;
+
+        loop {
+            let mut request = url
+                .as_str()
+                .into_client_request()
+                .map_err(api_error(&path))?;
+            let headers = request.headers_mut();
+            headers.insert(
+                "Sec-WebSocket-Protocol",
+                HeaderValue::from_static(PORT_FORWARD_PROTOCOL),
+            );
+            if let Some(authorization) = self.authorization(refresh)? {
+                headers.insert(
+                    "Authorization",
+                    HeaderValue::from_str(&authorization).map_err(api_error(&path))?,
+                );
+            }
+
+            match tungstenite::client(request, self.connect()?) {
+                Ok((websocket, _)) => return Ok(websocket),
+                Err(HandshakeError::Failure(tungstenite::Error::Http(response))) => {
+                    let status = response.status().as_u16();
+                    if status == 401 && self.can_refresh() && !refresh {
+                        refresh = true;
+                        continue;
+                    }
+                    let body = response
+                        .body()
+                        .as_ref()
+                        .map(|body| String::from_utf8_lossy(body).into_owned())
+                        .unwrap_or_default();
+                    return Err(api_error(&path)(status_message(status, &body)));
+                }
+                Err(e) => return Err(api_error(&path)(e)),
+            }
+        }
+    }
+
+    /// A TLS connection to the API server.
+    fn connect(&self) -> crate::Result<TlsStream> {
+        let addr = self
+            .authority
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| api_error(&self.authority)("address not found"))?;
+        let socket = TcpStream::connect_timeout(&addr, REQUEST_TIMEOUT)?;
+        socket.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+        socket.set_write_timeout(Some(REQUEST_TIMEOUT))?;
+        let connection = ClientConnection::new(self.tls.clone(), self.server_name.clone())
+            .map_err(api_error(&self.authority))?;
+
+        Ok(StreamOwned::new(connection, socket))
+    }
+
+    /// Accepts connections on `listen_addr:local_port` and forwards each one
+    /// to `port` of a ready pod of `pods` over its own port-forward
+    /// stream, until figcli exits. When the pod stops accepting streams the
+    /// next ready one is used, new clients wait for it up to `timeout`.
+    /// Connections are logged to stderr when `verbose` is set, failures
+    /// always are.
+    #[allow(clippy::too_many_arguments)]
+    pub fn spawn_forward(
+        self: Arc<Self>,
+        listen_addr: &str,
+        local_port: u16,
+        namespace: &str,
+        pods: &PodTarget,
+        port: u16,
+        timeout: Duration,
+        verbose: bool,
+    ) -> crate::Result<JoinHandle<()>> {
+        let pod = k8s::ready_pod(&self.namespace(namespace), pods)?;
+        let listener = TcpListener::bind((listen_addr, local_port))?;
+        println!(
+            "Forwarding from {}:{} -> pod/{}:{}",
+            listen_addr, local_port, pod, port
+        );
+
+        let target = Arc::new(Target {
+            namespace: namespace.to_owned(),
+            pods: pods.clone(),
+            port,
+            pod: Mutex::new(pod),
+            verbose,
+        });
+        let ids = Arc::new(AtomicUsize::new(0));
+
+        Ok(thread::spawn(move || {
+            for client in listener.incoming() {
+                let client = match client {
+                    Ok(client) => client,
+                    Err(e) => {
+                        eprintln!("port-forward: could not accept connection: {}", e);
+                        continue;
+                    }
+                };
+                let id = ids.fetch_add(1, Ordering::SeqCst) + 1;
+                let api = self.clone();
+                let target = target.clone();
+
+                thread::spawn(move || api.forward(id, client, &target, timeout));
+            }
+        }))
+    }
+
+    fn forward(&self, id: usize, client: TcpStream, target: &Target, timeout: Duration) {
+        let peer = client
+            .peer_addr()
+            .map(|addr| addr.to_string())
+            .unwrap_or_else(|_| "unknown".to_owned());
+        if target.verbose {
+            eprintln!("port-forward: connection #{} from {} opened", id, peer);
+        }
+
+        let websocket = match self.open(target, timeout) {
+            Ok(websocket) => websocket,
+            Err(e) => {
+                eprintln!(
+                    "port-forward: connection #{} from {} closed, pod unavailable: {}",
+                    id, peer, e
+                );
+                return;
+            }
+        };
+
+        let started = Instant::now();
+        match pump(client, websocket) {
+            Ok((sent, received)) if target.verbose => eprintln!(
+                "port-forward: connection #{} from {} closed after {}s ({} bytes sent, {} bytes received)",
+                id,
+                peer,
+                started.elapsed().as_secs(),
+                sent,
+                received
+            ),
+            Ok(_) => {}
+            Err(e) => eprintln!(
+                "port-forward: connection #{} from {} failed: {}",
+                id, peer, e
+            ),
+        }
+    }
+
+    /// Opens a stream to the target's pod, looking for another ready pod
+    /// while that fails, until `timeout` passes.
+    fn open(&self, target: &Target, timeout: Duration) -> crate::Result<WebSocket<TlsStream>> {
+        let deadline = Instant::now() + timeout;
+
+        loop {
+            let pod = target.pod()?;
+            match self.port_forward(&target.namespace, &pod, target.port) {
+                Ok(websocket) => return Ok(websocket),
+                Err(e) if Instant::now() >= deadline => return Err(e),
+                Err(_) => {
+                    thread::sleep(RETRY_INTERVAL);
+                    let namespace = self.namespace(&target.namespace);
+                    if let Ok(ready) = k8s::ready_pod(&namespace, &target.pods) {
+                        if ready != pod && target.verbose {
+                            eprintln!("port-forward: using pod/{}", ready);
+                        }
+                        target.set_pod(ready)?;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Where forwarded connections go. The pod changes when it goes away.
+struct Target {
+    namespace: String,
+    pods: PodTarget,
+    port: u16,
+    pod: Mutex<String>,
+    /// Whether routine connection events are logged.
+    verbose: bool,
+}
+
+impl Target {
+    fn pod(&self) -> crate::Result<String> {
+        Ok(self
+            .pod
+            .lock()
+            .map_err(|_| io::Error::other("pod lock poisoned"))?
+            .clone())
+    }
+
+    fn set_pod(&self, pod: String) -> crate::Result<()> {
+        *self
+            .pod
+            .lock()
+            .map_err(|_| io::Error::other("pod lock poisoned"))? = pod;
+
+        Ok(())
+    }
+}
+
+/// The objects of one namespace, as `k8s` reads them.
+struct Namespace<'a> {
+    client: &'a Client,
+    name: &'a str,
+}
+
+impl k8s::Api for Namespace<'_> {
+    fn get<T: DeserializeOwned>(&self, kind: &str, name: &str) -> crate::Result<T> {
+        let group = match kind {
+            "deployment" | "statefulset" => "/apis/apps/v1",
+            _ => "/api/v1",
+        };
+
+        self.client.get(&format!(
+            "{}/namespaces/{}/{}s/{}",
+            group, self.name, kind, name
+        ))
+    }
+
+    fn pods(&self, selector: &str) -> crate::Result<Vec<Pod>> {
+        let pods = self.client.get::<List<Pod>>(&format!(
+            "/api/v1/namespaces/{}/pods?labelSelector={}",
+            self.name,
+            query_encode(selector)
+        ))?;
+
+        Ok(pods.items)
+    }
+}
+
+/// Percent-encodes everything but the unreserved characters of RFC 3986.
+fn query_encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (b as char).to_string()
+            }
+            b => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+fn tls_config(access: &kubeconfig::Access) -> Result<ClientConfig, String> {
+    let mut roots = RootCertStore::empty();
+    match &access.certificate_authority {
+        Some(pem) => {
+            for certificate in CertificateDer::pem_slice_iter(pem) {
+                let certificate =
+                    certificate.map_err(|e| format!("invalid certificate authority: {}", e))?;
+                roots
+                    .add(certificate)
+                    .map_err(|e| format!("invalid certificate authority: {}", e))?;
+            }
+        }
+        None => roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned()),
+    }
+
+    let builder =
+        ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .map_err(|e| e.to_string())?
+            .with_root_certificates(roots);
+
+    match &access.client_certificate {
+        Some((certificate, key)) => {
+            let chain = CertificateDer::pem_slice_iter(certificate)
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|e| format!("invalid client certificate: {}", e))?;
+            let key = PrivateKeyDer::from_pem_slice(key)
+                .map_err(|e| format!("invalid client key: {}", e))?;
+            builder
+                .with_client_auth_cert(chain, key)
+                .map_err(|e| format!("invalid client certificate: {}", e))
+        }
+        None => Ok(builder.with_no_client_auth()),
+    }
+}
+
+/// Runs the credential plugin, which prints an `ExecCredential`.
+fn exec_token(exec: &ExecConfig) -> crate::Result<String> {
+    let api_version = exec
+        .api_version
+        .as_deref()
+        .unwrap_or(EXEC_CREDENTIAL_API_VERSION);
+    let mut cmd = Command::new(&exec.command);
+    cmd.args(exec.args.iter().flatten());
+    for var in exec.env.iter().flatten() {
+        cmd.env(&var.name, &var.value);
+    }
+    cmd.env(
+        "KUBERNETES_EXEC_INFO",
+        serde_json::json!({
+            "apiVersion": api_version,
+            "kind": "ExecCredential",
+            "spec": { "interactive": false },
+        })
+        .to_string(),
+    );
+
+    let output = runner::capture_command(&mut cmd)?;
+    serde_json::from_str::<ExecCredential>(&output)
+        .map_err(api_error(&exec.command))?
+        .status
+        .token
+        .ok_or_else(|| api_error(&exec.command)("the credential plugin returned no token"))
+}
+
+fn websocket_error(e: tungstenite::Error) -> io::Error {
+    match e {
+        tungstenite::Error::Io(e) => e,
+        e => io::Error::other(e),
+    }
+}
+
+/// Whether a websocket operation only has to be tried again later.
+fn would_block(result: &tungstenite::Result<()>) -> bool {
+    matches!(result, Err(tungstenite::Error::Io(e)) if e.kind() == io::ErrorKind::WouldBlock)
+}
+
+/// Copies between the client and the port-forward stream until either side
+/// closes or the pod reports an error, returning the bytes sent to and
+/// received from the pod.
+fn pump<S: Socket>(mut client: TcpStream, mut websocket: WebSocket<S>) -> io::Result<(u64, u64)> {
+    websocket.get_ref().tcp().set_nonblocking(true)?;
+    let (mut sent, mut received) = (0, 0);
+    let mut prefix = [PORT_PREFIX_LEN; 2];
+    let mut error = Vec::new();
+    let mut buffer = vec![0; BUFFER_SIZE];
+    // a message that couldn't be written in full yet, the client isn't read
+    // until it is
+    let mut pending = false;
+
+    'pump: loop {
+        let mut fds = [
+            libc::pollfd {
+                fd: client.as_raw_fd(),
+                events: if pending { 0 } else { libc::POLLIN },
+                revents: 0,
+            },
+            libc::pollfd {
+                fd: websocket.get_ref().tcp().as_raw_fd(),
+                events: libc::POLLIN | if pending { libc::POLLOUT } else { 0 },
+                revents: 0,
+            },
+        ];
+        if unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, POLL_TIMEOUT_MS) } < 0 {
+            let e = io::Error::last_os_error();
+            if e.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(e);
+        }
+
+        if fds[0].revents != 0 {
+            let n = client.read(&mut buffer)?;
+            if n == 0 {
+                let _ = websocket.close(None);
+                let _ = websocket.flush();
+                break;
+            }
+            let mut message = Vec::with_capacity(n + 1);
+            message.push(DATA_CHANNEL);
+            message.extend_from_slice(&buffer[..n]);
+            sent += n as u64;
+
+            let result = websocket.send(Message::Binary(message));
+            pending = would_block(&result);
+            if !pending {
+                result.map_err(websocket_error)?;
+            }
+        } else if pending {
+            let result = websocket.flush();
+            pending = would_block(&result);
+            if !pending {
+                result.map_err(websocket_error)?;
+            }
+        }
+
+        // read everything there is, including what the TLS layer buffered
+        loop {
+            match websocket.read() {
+                Ok(Message::Binary(data)) => {
+                    let (channel, mut payload) = match data.split_first() {
+                        Some((channel, payload)) if *channel <= ERROR_CHANNEL => {
+                            (*channel, payload)
+                        }
+                        _ => continue,
+                    };
+                    let skip = &mut prefix[channel as usize];
+                    let skipped = (*skip).min(payload.len());
+                    *skip -= skipped;
+                    payload = &payload[skipped..];
+
+                    if channel == DATA_CHANNEL {
+                        client.write_all(payload)?;
+                        received += payload.len() as u64;
+                    } else {
+                        error.extend_from_slice(payload);
+                    }
+                }
+                Ok(Message::Close(_)) => break 'pump,
+                Ok(_) => {}
+                Err(tungstenite::Error::Io(e)) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(tungstenite::Error::ConnectionClosed)
+                | Err(tungstenite::Error::AlreadyClosed) => break 'pump,
+                Err(e) => return Err(websocket_error(e)),
+            }
+        }
+        if !error.is_empty() {
+            break;
+        }
+    }
+
+    let _ = client.shutdown(Shutdown::Both);
+    if !error.is_empty() {
+        return Err(io::Error::other(
+            String::from_utf8_lossy(&error).into_owned(),
+        ));
+    }
+
+    Ok((sent, received))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tungstenite::protocol::Role;
+
+    impl Socket for TcpStream {
+        fn tcp(&self) -> &TcpStream {
+            self
+        }
+    }
+
+    type Pumped = io::Result<(u64, u64)>;
+
+    fn pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let near = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (far, _) = listener.accept().unwrap();
+
+        (near, far)
+    }
+
+    /// Runs `pump` between a local client and a stub of the pod's end of the
+    /// port-forward stream, both of which are returned.
+    fn start_pump() -> (TcpStream, WebSocket<TcpStream>, JoinHandle<Pumped>) {
+        let (client, pumped_client) = pair();
+        let (pumped_websocket, pod) = pair();
+        let pumped_websocket = WebSocket::from_raw_socket(pumped_websocket, Role::Client, None);
+        let pod = WebSocket::from_raw_socket(pod, Role::Server, None);
+
+        let pump = thread::spawn(move || pump(pumped_client, pumped_websocket));
+        (client, pod, pump)
+    }
+
+    fn frame(channel: u8, payload: &[u8]) -> Message {
+        let mut data = vec![channel];
+        data.extend_from_slice(payload);
+        Message::Binary(data)
+    }
+
+    /// The pod's first frame on a channel, the port number it belongs to.
+    fn port_prefix(channel: u8) -> Message {
+        frame(channel, &5432u16.to_le_bytes())
+    }
+
+    #[test]
+    fn pump_copies_data_without_the_port_prefix() {
+        let (mut client, mut pod, pump) = start_pump();
+
+        pod.send(port_prefix(DATA_CHANNEL)).unwrap();
+        pod.send(port_prefix(ERROR_CHANNEL)).unwrap();
+        pod.send(frame(DATA_CHANNEL, b"hello")).unwrap();
+        let mut greeting = [0; 5];
+        client.read_exact(&mut greeting).unwrap();
+        assert_eq!(&greeting, b"hello");
+
+        client.write_all(b"ping").unwrap();
+        match pod.read().unwrap() {
+            Message::Binary(data) => assert_eq!(data, b"\0ping"),
+            message => panic!("unexpected {:?}", message),
+        }
+
+        pod.close(None).unwrap();
+        let _ = pod.flush();
+        assert_eq!(pump.join().unwrap().unwrap(), (4, 5));
+    }
+
+    #[test]
+    fn pump_strips_a_port_prefix_split_across_frames() {
+        let (mut client, mut pod, pump) = start_pump();
+
+        pod.send(frame(DATA_CHANNEL, &[0x38])).unwrap();
+        pod.send(frame(DATA_CHANNEL, &[0x15, b'h', b'i'])).unwrap();
+        let mut data = [0; 2];
+        client.read_exact(&mut data).unwrap();
+        assert_eq!(&data, b"hi");
+
+        drop(client);
+        pod.read().unwrap();
+        assert_eq!(pump.join().unwrap().unwrap(), (0, 2));
+    }
+
+    #[test]
+    fn pump_fails_with_the_error_channel_message() {
+        let (mut client, mut pod, pump) = start_pump();
+
+        pod.send(port_prefix(ERROR_CHANNEL)).unwrap();
+        pod.send(frame(ERROR_CHANNEL, b"connection refused"))
+            .unwrap();
+
+        let error = pump.join().unwrap().unwrap_err();
+        assert_eq!(error.to_string(), "connection refused");
+        let mut rest = Vec::new();
+        client.read_to_end(&mut rest).unwrap();
+        assert!(rest.is_empty());
+    }
+
+    #[test]
+    fn pump_ignores_unknown_channels() {
+        let (mut client, mut pod, pump) = start_pump();
+
+        pod.send(port_prefix(DATA_CHANNEL)).unwrap();
+        pod.send(frame(7, b"noise")).unwrap();
+        pod.send(frame(DATA_CHANNEL, b"data")).unwrap();
+        let mut data = [0; 4];
+        client.read_exact(&mut data).unwrap();
+        assert_eq!(&data, b"data");
+
+        pod.close(None).unwrap();
+        let _ = pod.flush();
+        assert_eq!(pump.join().unwrap().unwrap(), (0, 4));
+    }
+}
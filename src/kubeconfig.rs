@@ -0,0 +1,402 @@
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+/// The parts of a kubeconfig file figcli understands.
+#[derive(Deserialize, Default)]
+struct Kubeconfig {
+    #[serde(default)]
+    clusters: Vec<NamedCluster>,
+    #[serde(default)]
+    users: Vec<NamedUser>,
+    #[serde(default)]
+    contexts: Vec<NamedContext>,
+}
+
+#[derive(Deserialize)]
+struct NamedCluster {
+    name: String,
+    cluster: Cluster,
+}
+
+#[derive(Deserialize)]
+struct NamedUser {
+    name: String,
+    #[serde(default)]
+    user: Option<User>,
+}
+
+#[derive(Deserialize)]
+struct NamedContext {
+    name: String,
+    context: Context,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct Cluster {
+    server: String,
+    certificate_authority: Option<PathBuf>,
+    certificate_authority_data: Option<String>,
+    #[serde(default)]
+    insecure_skip_tls_verify: bool,
+    tls_server_name: Option<String>,
+    proxy_url: Option<String>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "kebab-case")]
+struct User {
+    token: Option<String>,
+    #[serde(rename = "tokenFile")]
+    token_file: Option<PathBuf>,
+    client_certificate: Option<PathBuf>,
+    client_certificate_data: Option<String>,
+    client_key: Option<PathBuf>,
+    client_key_data: Option<String>,
+    username: Option<String>,
+    password: Option<String>,
+    exec: Option<ExecConfig>,
+    auth_provider: Option<serde_yaml::Value>,
+}
+
+#[derive(Deserialize)]
+struct Context {
+    cluster: String,
+    user: Option<String>,
+}
+
+/// A credential plugin, like `gke-gcloud-auth-plugin`, that prints an
+/// `ExecCredential` with a token.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ExecConfig {
+    pub command: String,
+    pub args: Option<Vec<String>>,
+    pub env: Option<Vec<ExecEnv>>,
+    pub api_version: Option<String>,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct ExecEnv {
+    pub name: String,
+    pub value: String,
+}
+
+#[derive(Clone, Debug)]
+pub enum Auth {
+    None,
+    Token(String),
+    Basic { username: String, password: String },
+    Exec(ExecConfig),
+}
+
+/// How to reach and log in to the API server of one context.
+pub struct Access {
+    pub server: String,
+    /// PEM certificates the server's certificate has to be signed by, the
+    /// public web PKI when there are none.
+    pub certificate_authority: Option<Vec<u8>>,
+    pub tls_server_name: Option<String>,
+    /// PEM certificate chain and key.
+    pub client_certificate: Option<(Vec<u8>, Vec<u8>)>,
+    pub auth: Auth,
+}
+
+/// The files of `KUBECONFIG`, or `~/.kube/config`.
+fn paths() -> Vec<PathBuf> {
+    match env::var_os("KUBECONFIG") {
+        Some(paths) if !paths.is_empty() => env::split_paths(&paths).collect(),
+        _ => dirs::home_dir()
+            .map(|home| vec![home.join(".kube").join("config")])
+            .unwrap_or_default(),
+    }
+}
+
+/// Reads `context` the way kubectl does: from every file in `KUBECONFIG`,
+/// where the first one to define a name wins. An error means the context
+/// can't be used without kubectl, e.g. because it needs an auth provider.
+pub fn load(context: &str) -> Result<Access, String> {
+    load_from(&paths(), context)
+}
+
+fn load_from(paths: &[PathBuf], context: &str) -> Result<Access, String> {
+    let mut merged = Kubeconfig::default();
+    for path in paths {
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(_) => continue,
+        };
+        let mut kubeconfig: Kubeconfig = serde_yaml::from_str(&contents)
+            .map_err(|e| format!("could not parse {}: {}", path.display(), e))?;
+        resolve_paths(
+            &mut kubeconfig,
+            path.parent().unwrap_or_else(|| Path::new(".")),
+        );
+
+        merged.clusters.extend(kubeconfig.clusters);
+        merged.users.extend(kubeconfig.users);
+        merged.contexts.extend(kubeconfig.contexts);
+    }
+
+    let context = merged
+        .contexts
+        .iter()
+        .find(|c| c.name == context)
+        .map(|c| &c.context)
+        .ok_or_else(|| format!("context {} not found in the kubeconfig", context))?;
+    let cluster = merged
+        .clusters
+        .iter()
+        .find(|c| c.name == context.cluster)
+        .map(|c| &c.cluster)
+        .ok_or_else(|| format!("cluster {} not found in the kubeconfig", context.cluster))?;
+    let user = match &context.user {
+        Some(name) => merged
+            .users
+            .iter()
+            .find(|u| &u.name == name)
+            .ok_or_else(|| format!("user {} not found in the kubeconfig", name))?
+            .user
+            .as_ref(),
+        None => None,
+    };
+    let default_user = User::default();
+    let user = user.unwrap_or(&default_user);
+
+    if cluster.insecure_skip_tls_verify {
+        return Err("insecure-skip-tls-verify is not supported".to_owned());
+    }
+    if cluster.proxy_url.is_some() {
+        return Err("proxy-url is not supported".to_owned());
+    }
+    if user.auth_provider.is_some() {
+        return Err("auth-provider is not supported".to_owned());
+    }
+    if !cluster.server.starts_with("https://") {
+        return Err(format!("{} is not an https server", cluster.server));
+    }
+
+    let client_certificate = match (
+        data_or_file(&user.client_certificate_data, &user.client_certificate)?,
+        data_or_file(&user.client_key_data, &user.client_key)?,
+    ) {
+        (Some(certificate), Some(key)) => Some((certificate, key)),
+        (None, None) => None,
+        _ => return Err("a client certificate needs both a certificate and a key".to_owned()),
+    };
+    let auth = if let Some(exec) = &user.exec {
+        Auth::Exec(exec.clone())
+    } else if let Some(token) = &user.token {
+        Auth::Token(token.clone())
+    } else if let Some(token_file) = &user.token_file {
+        let token = fs::read_to_string(token_file)
+            .map_err(|e| format!("could not read {}: {}", token_file.display(), e))?;
+        Auth::Token(token.trim().to_owned())
+    } else if let (Some(username), Some(password)) = (&user.username, &user.password) {
+        Auth::Basic {
+            username: username.clone(),
+            password: password.clone(),
+        }
+    } else {
+        Auth::None
+    };
+
+    Ok(Access {
+        server: cluster.server.trim_end_matches('/').to_owned(),
+        certificate_authority: data_or_file(
+            &cluster.certificate_authority_data,
+            &cluster.certificate_authority,
+        )?,
+        tls_server_name: cluster.tls_server_name.clone(),
+        client_certificate,
+        auth,
+    })
+}
+
+/// Paths in a kubeconfig are relative to the file they are in.
+fn resolve_paths(kubeconfig: &mut Kubeconfig, dir: &Path) {
+    let resolve = |path: &mut Option<PathBuf>| {
+        if let Some(p) = path.as_mut() {
+            if p.is_relative() {
+                *p = dir.join(&p);
+            }
+        }
+    };
+
+    for cluster in &mut kubeconfig.clusters {
+        resolve(&mut cluster.cluster.certificate_authority);
+    }
+    for user in kubeconfig.users.iter_mut().filter_map(|u| u.user.as_mut()) {
+        resolve(&mut user.token_file);
+        resolve(&mut user.client_certificate);
+        resolve(&mut user.client_key);
+    }
+}
+
+/// The base64 `*-data` value if there is one, the contents of the file
+/// otherwise.
+fn data_or_file(data: &Option<String>, file: &Option<PathBuf>) -> Result<Option<Vec<u8>>, String> {
+    match (data, file) {
+        (Some(data), _) => base64::decode(data.trim())
+            .map(Some)
+            .map_err(|e| format!("invalid base64 in the kubeconfig: {}", e)),
+        (None, Some(file)) => fs::read(file)
+            .map(Some)
+            .map_err(|e| format!("could not read {}: {}", file.display(), e)),
+        (None, None) => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A directory with the kubeconfig `files`, removed when dropped.
+    struct Dir(PathBuf);
+
+    impl Dir {
+        fn with(files: &[(&str, &str)]) -> Self {
+            let dir = env::temp_dir().join(format!(
+                "figcli-kubeconfig-{}",
+                crate::util::random_alphanum(8)
+            ));
+            fs::create_dir_all(&dir).unwrap();
+            for (name, contents) in files {
+                fs::write(dir.join(name), contents).unwrap();
+            }
+            Dir(dir)
+        }
+
+        fn paths(&self, names: &[&str]) -> Vec<PathBuf> {
+            names.iter().map(|name| self.0.join(name)).collect()
+        }
+    }
+
+    impl Drop for Dir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    const DEV: &str = "
+clusters:
+- name: dev
+  cluster:
+    server: https://dev.example.com/
+    certificate-authority-data: Y2E=
+contexts:
+- name: dev
+  context:
+    cluster: dev
+    user: dev-user
+- name: prod
+  context:
+    cluster: prod
+    user: prod-user
+users:
+- name: dev-user
+  user:
+    token: dev-token
+";
+
+    const PROD: &str = "
+clusters:
+- name: dev
+  cluster:
+    server: https://shadowed.example.com
+- name: prod
+  cluster:
+    server: https://prod.example.com
+    certificate-authority: ca.pem
+contexts:
+- name: dev
+  context:
+    cluster: prod
+    user: prod-user
+users:
+- name: prod-user
+  user:
+    tokenFile: token
+";
+
+    fn token(access: &Access) -> &str {
+        match &access.auth {
+            Auth::Token(token) => token,
+            auth => panic!("unexpected auth {:?}", auth),
+        }
+    }
+
+    #[test]
+    fn first_file_to_define_a_name_wins() {
+        let dir = Dir::with(&[
+            ("dev", DEV),
+            ("prod", PROD),
+            ("ca.pem", "PEM"),
+            ("token", "prod-token\n"),
+        ]);
+        let access = load_from(&dir.paths(&["missing", "dev", "prod"]), "dev").unwrap();
+
+        assert_eq!(access.server, "https://dev.example.com");
+        assert_eq!(access.certificate_authority.as_deref(), Some(&b"ca"[..]));
+        assert_eq!(token(&access), "dev-token");
+    }
+
+    #[test]
+    fn context_picks_cluster_and_user_across_files() {
+        let dir = Dir::with(&[
+            ("dev", DEV),
+            ("prod", PROD),
+            ("ca.pem", "PEM"),
+            ("token", "prod-token\n"),
+        ]);
+        let access = load_from(&dir.paths(&["dev", "prod"]), "prod").unwrap();
+
+        assert_eq!(access.server, "https://prod.example.com");
+        // relative to the file that names them
+        assert_eq!(access.certificate_authority.as_deref(), Some(&b"PEM"[..]));
+        assert_eq!(token(&access), "prod-token");
+    }
+
+    #[test]
+    fn unknown_context_is_an_error() {
+        let dir = Dir::with(&[("dev", DEV)]);
+        let error = load_from(&dir.paths(&["dev"]), "staging").err().unwrap();
+
+        assert_eq!(error, "context staging not found in the kubeconfig");
+    }
+
+    #[test]
+    fn settings_figcli_cannot_honour_are_refused() {
+        let kubeconfig = |cluster: &str, user: &str| {
+            format!(
+                "clusters:\n- name: c\n  cluster:\n    server: https://c.example.com\n{}\
+                 contexts:\n- name: c\n  context:\n    cluster: c\n    user: u\n\
+                 users:\n- name: u\n  user:\n{}",
+                cluster, user
+            )
+        };
+        let cases = [
+            (
+                kubeconfig("    insecure-skip-tls-verify: true\n", "    token: t\n"),
+                "insecure-skip-tls-verify is not supported",
+            ),
+            (
+                kubeconfig("    proxy-url: http://proxy:3128\n", "    token: t\n"),
+                "proxy-url is not supported",
+            ),
+            (
+                kubeconfig("", "    auth-provider:\n      name: gcp\n"),
+                "auth-provider is not supported",
+            ),
+        ];
+
+        for (contents, expected) in cases.iter() {
+            let dir = Dir::with(&[("config", contents)]);
+            let error = load_from(&dir.paths(&["config"]), "c").err().unwrap();
+            assert_eq!(&error, expected);
+        }
+    }
+}
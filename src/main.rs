@@ -3,52 +3,176 @@ extern crate quick_error;
 #[macro_use]
 extern crate prettytable;
 
-use std::io::Write;
+use std::io::{self, Write};
+use std::net::TcpStream;
 use std::os::unix::fs::OpenOptionsExt;
 use std::path::{Path, PathBuf, StripPrefixError};
 use std::process::Command;
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::Duration;
 use std::{env, fs};
 
-use crate::config::{Config, PortForwardConfig, PostgresConfig, ServerConfigType};
-use crate::runner::run_command;
-use crate::util::ForwardingInfo;
+use crate::config::{
+    CloudSqlConfig, Config, Credentials, PasswordSource, PortForwardConfig, PostgresConfig,
+    RelayPodConfig, ServerConfigType,
+};
+use crate::k8s::PodTarget;
+use crate::runner::{run_command, Readiness};
+use crate::util::{ForwardingInfo, Remote};
 use clap::{value_t, App, Arg, SubCommand};
-use config::{environment_type, get_config, EnvironmentType};
+use config::get_config;
 use consts::*;
 use prettytable::{format::consts::FORMAT_NO_BORDER_LINE_SEPARATOR, Table};
+use regex::Regex;
 use uuid::Uuid;
 use walkdir::WalkDir;
 
+mod cloudsql;
 mod config;
 mod consts;
+mod guard;
+mod k8s;
+mod kubeapi;
+mod kubeconfig;
+mod pgproxy;
+mod relay;
 mod runner;
+mod scan;
+mod scram;
+mod secret;
+mod signal;
+mod ssh;
 mod util;
+mod vault;
 
 pub type Result<T> = std::result::Result<T, FigError>;
 
 quick_error! {
     #[derive(Debug)]
     pub enum FigError {
-        ConfigError(s: String) {}
-        DoctorError(s: String) {}
-        ExecError(s: String) {}
-        EnvError(s: String) {}
-        ParseError(s: String) {}
+        ConfigError(s: String) {
+            display("configuration error: {}", s)
+        }
+        ConfirmationError(s: String) {
+            display("not confirmed: {}", s)
+        }
+        ConfigNotFound(path: PathBuf) {
+            display("no configuration file found at {}", path.display())
+        }
+        DoctorError(s: String) {
+            display("{}", s)
+        }
+        ExecError(e: runner::ExecFailure) {
+            display("{}", e)
+        }
+        EnvError(s: String) {
+            display("invalid environment: {}", s)
+        }
+        ParseError(s: String) {
+            display("{}", s)
+        }
+        KubernetesError(s: String) {
+            display("kubernetes error: {}", s)
+        }
         IoError(e: std::io::Error) {
+            display("I/O error: {}", e)
             from()
         }
         StripPrefixError(e: StripPrefixError) {
+            display("unexpected configuration path: {}", e)
             from()
         }
         TomlError(e: toml::de::Error) {
+            display("invalid configuration file: {}", e)
             from()
         }
         WalkdirError(e: walkdir::Error) {
+            display("could not read configuration directory: {}", e)
             from()
         }
         UuidError(e: uuid::Error) {
+            display("invalid UUID: {}", e)
             from()
         }
+        VaultError(s: String) {
+            display("vault error: {}", s)
+        }
+    }
+}
+
+impl FigError {
+    /// The process exit code for each variant. Codes follow sysexits(3) where
+    /// one fits; an `ExecError` passes the failed command's own status through.
+    /// That status isn't remapped, so a child that itself follows sysexits(3)
+    /// (`psql` exits 64–78 on some failures) can collide with figcli's own
+    /// codes; the printed error tells the two apart.
+    ///
+    /// | variant          | code                              |
+    /// |------------------|-----------------------------------|
+    /// | ExecError        | child's status, 128 + signal, 69  |
+    /// | KubernetesError  | 69                                |
+    /// | ParseError       | 64                                |
+    /// | EnvError         | 65                                |
+    /// | ConfigNotFound   | 66                                |
+    /// | UuidError        | 67                                |
+    /// | TomlError        | 68                                |
+    /// | StripPrefixError | 70                                |
+    /// | WalkdirError     | 71                                |
+    /// | DoctorError      | 72                                |
+    /// | IoError          | 74                                |
+    /// | VaultError       | 76                                |
+    /// | ConfirmationError| 77                                |
+    /// | ConfigError      | 78                                |
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            FigError::ExecError(failure) => failure.exit_code(),
+            FigError::KubernetesError(_) => 69,
+            FigError::ParseError(_) => 64,
+            FigError::EnvError(_) => 65,
+            FigError::ConfigNotFound(_) => 66,
+            FigError::UuidError(_) => 67,
+            FigError::TomlError(_) => 68,
+            FigError::StripPrefixError(_) => 70,
+            FigError::WalkdirError(_) => 71,
+            FigError::DoctorError(_) => 72,
+            FigError::IoError(_) => 74,
+            FigError::VaultError(_) => 76,
+            FigError::ConfirmationError(_) => 77,
+            FigError::ConfigError(_) => 78,
+        }
+    }
+
+    /// A suggestion for how to fix the error, if there is an obvious one.
+    pub fn hint(&self) -> Option<String> {
+        match self {
+            FigError::ConfigNotFound(path) => {
+                let name = path.file_stem().and_then(|s| s.to_str()).unwrap_or("default");
+                let config_arg = if name == "default" {
+                    "".to_owned()
+                } else {
+                    format!("-c {} ", name)
+                };
+                Some(format!(
+                    "run `figcli {}config init` to create one, or `figcli config list` to see the existing ones",
+                    config_arg
+                ))
+            }
+            FigError::TomlError(_) | FigError::ConfigError(_) => Some(
+                "run `figcli config edit` to fix the configuration file (add `-c <name>` for a non default one)"
+                    .to_owned(),
+            ),
+            FigError::DoctorError(_) => Some(
+                "install the missing tools, `figcli doctor` checks for all of them".to_owned(),
+            ),
+            FigError::ConfirmationError(_) => Some(
+                "type the environment name exactly, or pass it with `--confirm <ENV>`".to_owned(),
+            ),
+            FigError::VaultError(_) => Some(
+                "check VAULT_ADDR and VAULT_TOKEN, or run `vault login`".to_owned(),
+            ),
+            _ => None,
+        }
     }
 }
 
@@ -137,9 +261,32 @@ type: Opaque"
     Ok(())
 }
 
+/// The port the relay pod listens on for each forwarding: the remote port,
+/// unless another forwarding already uses it, then a port no forwarding
+/// uses.
+fn relay_pod_ports(forwardings: &[&ForwardingInfo]) -> Vec<u16> {
+    let is_free = |port: u16, taken: &[u16]| {
+        !taken.contains(&port) && forwardings.iter().all(|f| f.remote_port != port)
+    };
+    let mut ports: Vec<u16> = Vec::with_capacity(forwardings.len());
+
+    for forwarding in forwardings {
+        let port = if ports.contains(&forwarding.remote_port) {
+            (20_000..)
+                .find(|port| is_free(*port, &ports))
+                .expect("ran out of relay pod ports")
+        } else {
+            forwarding.remote_port
+        };
+        ports.push(port);
+    }
+
+    ports
+}
+
 fn k8s_port_forward(
     config: Option<&PortForwardConfig>,
-    forwarding: &ForwardingInfo,
+    forwardings: &[ForwardingInfo],
     context: Option<&str>,
     namespace: Option<&str>,
 ) -> Result<()> {
@@ -147,65 +294,186 @@ fn k8s_port_forward(
         Some(config) => (Some(config.context.as_str()), config.namespace.as_deref()),
         None => (None, None),
     };
+    let relay_pod = config
+        .map(|config| config.relay_pod.clone())
+        .unwrap_or_default();
 
     // override the values from the config if `context` and `namespace` are explicitly provided:
-    let context_arg = context
-        .or(config_context)
-        .map_or_else(|| "".to_owned(), |c| format!("--context={}", c));
-    let namespace_arg = namespace
-        .or(config_namespace)
-        .map_or_else(|| "".to_owned(), |n| format!("--namespace={}", n));
+    let mut port_forward_script = port_forward_script(
+        context.or(config_context),
+        namespace.or(config_namespace),
+        &relay_pod,
+        forwardings,
+    )?;
+
+    let mut table = Table::new();
+    table.set_format(*FORMAT_NO_BORDER_LINE_SEPARATOR);
+    table.set_titles(row!["LOCAL", "REMOTE"]);
+    for forwarding in forwardings {
+        table.add_row(row![
+            format!("localhost:{}", forwarding.local_port),
+            format!("{}:{}", forwarding.remote, forwarding.remote_port)
+        ]);
+    }
+    table.printstd();
+
+    runner::run_command(&mut port_forward_script, None, false)
+}
+
+/// Writes the script that forwards `forwardings`, hosts through a relay pod
+/// that it deletes again on exit, and returns the command running it.
+fn port_forward_script(
+    context: Option<&str>,
+    namespace: Option<&str>,
+    relay_pod: &RelayPodConfig,
+    forwardings: &[ForwardingInfo],
+) -> Result<Command> {
+    for (i, forwarding) in forwardings.iter().enumerate() {
+        // remotes end up in shell commands
+        let is_name = |c: char| c.is_ascii_alphanumeric() || c == '.' || c == '-';
+        let valid = match &forwarding.remote {
+            Remote::Host(host) => !host.is_empty() && host.chars().all(is_name),
+            Remote::Resource(resource) => match resource.split_once('/') {
+                Some((kind, name)) => {
+                    !kind.is_empty()
+                        && !name.is_empty()
+                        && resource.chars().all(|c| c == '/' || is_name(c))
+                }
+                None => false,
+            },
+        };
+        if !valid {
+            return Err(FigError::ParseError(format!(
+                "invalid remote {:?}",
+                forwarding.remote.to_string()
+            )));
+        }
+        if forwardings[..i]
+            .iter()
+            .any(|f| f.local_port == forwarding.local_port)
+        {
+            return Err(FigError::ParseError(format!(
+                "local port {} is used by more than one forwarding",
+                forwarding.local_port
+            )));
+        }
+    }
+
+    let context_arg = context.map_or_else(|| "".to_owned(), |c| format!("--context={}", c));
+    let namespace_arg = namespace.map_or_else(|| "".to_owned(), |n| format!("--namespace={}", n));
     let pod_name = format!("figcli-temp-port-forward-{}", util::random_alphanum(8));
 
+    // one relay pod forwards to every host, with a socat per forwarding
+    let relayed = forwardings
+        .iter()
+        .filter(|f| matches!(f.remote, Remote::Host(_)))
+        .collect::<Vec<_>>();
+    let pod_ports = relay_pod_ports(&relayed);
+    let socat_commands = relayed
+        .iter()
+        .zip(&pod_ports)
+        .map(|(forwarding, pod_port)| {
+            format!(
+                "socat -d -d tcp-listen:{},fork,reuseaddr tcp-connect:{}:{} & ",
+                pod_port, forwarding.remote, forwarding.remote_port
+            )
+        })
+        .collect::<String>()
+        + "wait";
+
+    // and one `kubectl port-forward` per pod or service, with all its ports
+    let mut targets: Vec<(String, Vec<String>)> = Vec::new();
+    if !relayed.is_empty() {
+        let port_mappings = relayed
+            .iter()
+            .zip(&pod_ports)
+            .map(|(forwarding, pod_port)| format!("{}:{}", forwarding.local_port, pod_port))
+            .collect();
+        targets.push((format!("pod/{}", pod_name), port_mappings));
+    }
+    for forwarding in forwardings {
+        if let Remote::Resource(resource) = &forwarding.remote {
+            let port_mapping = format!("{}:{}", forwarding.local_port, forwarding.remote_port);
+            match targets.iter_mut().find(|(target, _)| target == resource) {
+                Some((_, port_mappings)) => port_mappings.push(port_mapping),
+                None => targets.push((resource.clone(), vec![port_mapping])),
+            }
+        }
+    }
+    let forward_commands = targets
+        .iter()
+        .map(|(target, port_mappings)| {
+            format!("forward {} {} &\n", target, port_mappings.join(" "))
+        })
+        .collect::<String>();
+
     let source_contents = format!(
         include_str!("../template/kubectl-port-forward-remote-host.sh.template"),
         temp_pod_name = pod_name,
         context_arg = context_arg,
         namespace_arg = namespace_arg,
-        local_port = forwarding.local_port,
-        remote_host = forwarding.remote_host,
-        remote_port = forwarding.remote_port
+        use_relay_pod = !relayed.is_empty(),
+        overrides = util::shell_quote(&k8s::relay_pod_overrides(relay_pod, &pod_name)),
+        image = util::shell_quote(k8s::relay_pod_image(relay_pod)),
+        socat_commands = socat_commands,
+        forward_commands = forward_commands
     );
 
-    // Write the parameterized template out as a shell script to execute:
+    // Write the parameterized template out as a shell script to execute, it
+    // has to be closed before it can be run
     let shell_script_name = util::temp_file("sh");
-    let mut shell_script_file = fs::OpenOptions::new()
+    fs::OpenOptions::new()
         .write(true)
         .create_new(true)
         .mode(0o700)
-        .open(&shell_script_name)?;
+        .open(&shell_script_name)?
+        .write_all(source_contents.as_bytes())?;
 
-    shell_script_file.write_all(source_contents.as_bytes())?;
-
-    let mut port_forward_script = Command::new(shell_script_name.to_str().unwrap());
+    Ok(Command::new(shell_script_name.to_str().unwrap()))
+}
 
-    println!(
-        "Forwarding {}:{} -> {}",
-        forwarding.remote_host, forwarding.remote_port, forwarding.local_port
-    );
+/// Session options for the configured schema and read-only mode, in the
+/// format of the `options` startup parameter (`PGOPTIONS`).
+fn postgres_options(config: &PostgresConfig) -> String {
+    let mut options = format!("--search_path={}", &config.schema());
+    if config.read_only {
+        options.push_str(" -c default_transaction_read_only=on");
+    }
 
-    runner::run_command(&mut port_forward_script, None, false)
+    options
 }
 
-fn postgres_shell_cmd(config: &PostgresConfig, port: u16) -> Command {
+fn postgres_shell_cmd(
+    config: &PostgresConfig,
+    credentials: &Credentials,
+    env: &str,
+    port: u16,
+) -> Command {
     let mut cmd = Command::new("psql");
 
-    let port = match &config._type {
-        ServerConfigType::Kubernetes { .. } => port,
-        ServerConfigType::GCloudProxy { .. } => port,
-        ServerConfigType::Direct => config.port(),
+    let (host, port) = match &config._type {
+        ServerConfigType::Direct => (config.host(), config.port()),
+        ServerConfigType::GCloudProxy(cloudsql) => match cloudsql::socket_dir(cloudsql) {
+            Some(dir) => (dir.display().to_string(), cloudsql::SOCKET_PORT),
+            None => ("localhost".to_owned(), port),
+        },
+        _ => ("localhost".to_owned(), port),
     };
 
-    if let Some(password) = &config.password {
+    if let Some(password) = &credentials.password {
         cmd.env("PGPASSWORD", password);
     }
 
-    cmd.env("PGOPTIONS", format!("--search_path={}", &config.schema()));
+    cmd.env("PGOPTIONS", postgres_options(config));
+    cmd.arg(format!(
+        "--set=PROMPT1={}",
+        guard::psql_prompt(env, config.prompt_color(env))
+    ));
     cmd.args(vec![
         "-h",
-        &config.host(),
+        &host,
         "-U",
-        &config.user,
+        &credentials.user,
         "-p",
         &port.to_string(),
         &config.database,
@@ -216,67 +484,309 @@ fn postgres_shell_cmd(config: &PostgresConfig, port: u16) -> Command {
 
 fn postgres_tunnel_cmd(config: &PostgresConfig, port: u16) -> Result<Option<Command>> {
     match &config._type {
-        ServerConfigType::Kubernetes {
+        ServerConfigType::Kubernetes(kubernetes) => {
+            // kubectl picks a pod of a deployment itself, anything else is
+            // resolved to its ready (primary) pod whenever the command is
+            // built, i.e. again for every restart, so the port is always
+            // the pod's
+            let resource = match kubernetes.target()? {
+                target @ PodTarget::Deployment(_) | target @ PodTarget::Pod(_) => {
+                    target.to_string()
+                }
+                target => format!(
+                    "pod/{}",
+                    k8s::ready_pod(&k8s::Kubectl(kubernetes), &target)?
+                ),
+            };
+            let port_mapping_spec = format!("{}:{}", port, &config.port());
+
+            let mut cmd = k8s::kubectl(kubernetes);
+            if let Some(container) = &kubernetes.container {
+                cmd.args(vec!["-c", container]);
+            }
+            cmd.args(vec!["port-forward", &resource, &port_mapping_spec]);
+
+            Ok(Some(cmd))
+        }
+        ServerConfigType::KubernetesRelay {
             context,
             namespace,
-            deployment,
-            container,
+            remote_host,
+            remote_port,
+            relay_pod,
         } => {
-            let mut cmd = Command::new("kubectl");
-            let deployment_spec = format!("deployment/{}", deployment);
-            let port_mapping_spec = format!("{}:{}", port, &config.port());
+            let forwarding = ForwardingInfo {
+                local_port: port,
+                remote: Remote::Host(remote_host.clone()),
+                remote_port: *remote_port,
+            };
+
+            Ok(Some(port_forward_script(
+                Some(context),
+                Some(namespace),
+                relay_pod,
+                &[forwarding],
+            )?))
+        }
+        ServerConfigType::GCloudProxy(cloudsql) => Ok(Some(cloudsql::proxy_cmd(cloudsql, port)?)),
+        ServerConfigType::Ssh(ssh) => Ok(Some(ssh::tunnel_cmd(
+            ssh,
+            port,
+            &config.host(),
+            config.port(),
+        )?)),
+        ServerConfigType::Direct => Ok(None),
+    }
+}
 
-            let mut parts: Vec<&str> = vec!["--context", context, "--namespace", namespace];
-            if let Some(container) = container {
-                parts.extend(&vec!["-c", container]);
-            }
-            parts.extend(&vec!["port-forward", &deployment_spec, &port_mapping_spec]);
+/// Readiness criteria for the tunnel started by `postgres_tunnel_cmd`. Both
+/// the local port and the tool specific "ready" log line are watched.
+fn postgres_tunnel_readiness(config: &PostgresConfig, port: u16, timeout: Duration) -> Readiness {
+    let patterns = match &config._type {
+        ServerConfigType::Kubernetes { .. } | ServerConfigType::KubernetesRelay { .. } => {
+            vec!["Forwarding from"]
+        }
+        // v1 and v2 of the proxy
+        ServerConfigType::GCloudProxy { .. } => {
+            vec!["Ready for new connections", "ready for new connections"]
+        }
+        // ssh -N prints nothing once the forward is up, only the port tells
+        ServerConfigType::Ssh(_) | ServerConfigType::Direct => vec![],
+    };
+    // a proxy on a unix socket has no port to watch
+    let port = match &config._type {
+        ServerConfigType::GCloudProxy(cloudsql) if cloudsql.unix_socket.is_some() => None,
+        _ => Some(port),
+    };
 
-            cmd.args(parts);
+    Readiness::new(port, patterns, timeout)
+}
 
-            Ok(Some(cmd))
+/// What connects a local port to the database.
+enum Tunnel {
+    /// The database is reached directly.
+    None,
+    /// Builds the command to start, like `kubectl port-forward`, and builds
+    /// it again for every restart.
+    Command(Box<dyn FnMut() -> Result<Command>>),
+    /// figcli's own port-forward over the Kubernetes API, already running.
+    Native(JoinHandle<()>),
+}
+
+impl Tunnel {
+    /// The command `run_command` has to start and wait for first, if any.
+    fn parent<'a>(
+        &'a mut self,
+        readiness: &'a Readiness,
+    ) -> Option<(&'a mut dyn FnMut() -> Result<Command>, &'a Readiness)> {
+        match self {
+            Tunnel::Command(cmd) => Some((cmd.as_mut(), readiness)),
+            _ => None,
         }
-        ServerConfigType::GCloudProxy { instance } => {
-            let mut cmd = Command::new("cloud_sql_proxy");
-            cmd.args(vec!["-instances", &format!("{}=tcp:{}", instance, port)]);
+    }
 
-            Ok(Some(cmd))
+    /// Keeps the tunnel up until figcli is stopped.
+    fn run(self) -> Result<()> {
+        match self {
+            Tunnel::None => Ok(()),
+            Tunnel::Command(cmd) => runner::run_supervised(cmd),
+            Tunnel::Native(forward) => forward
+                .join()
+                .map_err(|_| FigError::IoError(io::Error::other("port-forward stopped"))),
         }
-        ServerConfigType::Direct => Ok(None),
     }
 }
 
-fn postgres_pgbouncer_cmd(
+/// Connects `port` to the database. Kubernetes is port-forwarded to over
+/// its API, unless `use_kubectl` is set or the kubeconfig context needs
+/// kubectl (e.g. for an auth provider), everything else is left to the
+/// command of `postgres_tunnel_cmd`. `verbose` logs every forwarded
+/// connection, which an interactive shell on the same terminal doesn't want.
+fn postgres_tunnel(
     config: &PostgresConfig,
     port: u16,
+    ready_timeout: Duration,
+    use_kubectl: bool,
+    verbose: bool,
+) -> Result<Tunnel> {
+    if let (ServerConfigType::Kubernetes(kubernetes), false) = (&config._type, use_kubectl) {
+        match kubeapi::Client::new(&kubernetes.context) {
+            Ok(client) => {
+                let forward = Arc::new(client).spawn_forward(
+                    "127.0.0.1",
+                    port,
+                    &kubernetes.namespace,
+                    &kubernetes.target()?,
+                    config.port(),
+                    ready_timeout,
+                    verbose,
+                )?;
+                return Ok(Tunnel::Native(forward));
+            }
+            Err(reason) => println!("Using kubectl port-forward: {}", reason),
+        }
+    }
+
+    // the first command is built right away so configuration errors show up
+    // before anything starts, restarts build their own (e.g. to forward to
+    // the current primary, or through a new relay pod)
+    let mut first = match postgres_tunnel_cmd(config, port)? {
+        Some(cmd) => Some(cmd),
+        None => return Ok(Tunnel::None),
+    };
+    let config = config.clone();
+    Ok(Tunnel::Command(Box::new(move || match first.take() {
+        Some(cmd) => Ok(cmd),
+        None => postgres_tunnel_cmd(&config, port)?
+            .ok_or_else(|| FigError::ConfigError("nothing to tunnel".to_owned())),
+    })))
+}
+
+/// One local pgbouncer: its files, all in a private directory that is
+/// removed when `figcli` exits, and the user clients log in with. The user
+/// and password are generated for every session and only accepted over
+/// SCRAM.
+#[derive(Clone)]
+struct PgbouncerSession {
+    ini: PathBuf,
+    auth_file: PathBuf,
+    pid_file: PathBuf,
+    listen_addr: String,
+    port: u16,
     upstream_port: u16,
-) -> Result<Command> {
-    let ini_file_path_str = util::temp_file("ini");
-    let mut ini_file = fs::File::create(&ini_file_path_str)?;
+    user: String,
+    password: String,
+    scram_secret: String,
+}
+
+impl PgbouncerSession {
+    fn new(listen_addr: &str, port: u16, upstream_port: u16) -> Result<Self> {
+        let dir = util::private_temp_dir()?;
+        let password = util::random_alphanum(32);
+
+        Ok(PgbouncerSession {
+            ini: dir.join("pgbouncer.ini"),
+            auth_file: dir.join("users.txt"),
+            pid_file: dir.join("pgbouncer.pid"),
+            listen_addr: listen_addr.to_owned(),
+            port,
+            upstream_port,
+            user: format!("figcli_{}", util::random_alphanum(8).to_lowercase()),
+            scram_secret: scram::secret(&password),
+            password,
+        })
+    }
+
+    fn connection_string(&self, database: &str) -> String {
+        format!(
+            "postgresql://{}:{}@{}:{}/{}",
+            self.user,
+            self.password,
+            util::url_host(util::connect_host(&self.listen_addr)),
+            self.port,
+            database
+        )
+    }
+}
+
+/// The `auth_file` with the session user's SCRAM secret for clients, and the
+/// server user's password as it is: pgbouncer needs the plain password to log
+/// in to servers that only accept SCRAM, or a cleartext IAM token. The file is
+/// only readable by the current user and lives in the private session
+/// directory.
+fn pgbouncer_auth_file(session: &PgbouncerSession, user: &str, password: &str) -> String {
+    // auth_file fields are double quoted, a quote inside is doubled
+    let quote = |s: &str| format!("\"{}\"", s.replace('"', "\"\""));
+
+    format!(
+        "{} {}\n{} {}\n",
+        quote(&session.user),
+        quote(&session.scram_secret),
+        quote(user),
+        quote(password)
+    )
+}
 
-    let password = config.password.as_ref().ok_or_else(|| {
+/// Writes the pgbouncer ini and auth file for `credentials`, (re)writing them
+/// in place so a running pgbouncer picks up new credentials on `SIGHUP`.
+fn write_pgbouncer_ini(
+    session: &PgbouncerSession,
+    config: &PostgresConfig,
+    credentials: &Credentials,
+) -> Result<()> {
+    let password = credentials.password.as_ref().ok_or_else(|| {
         FigError::ConfigError("password required when using pgbouncer".to_owned())
     })?;
 
-    // TODO change to md5 hash of password
-    // TODO remove
-    println!("{}", ini_file_path_str.display());
-    println!("\"{}\" \"{}\"", &config.user, &password);
-
     let ini_content = format!(
         include_str!("../template/pgbouncer.toml.template"),
         database = config.database,
-        upstream_port = upstream_port,
-        user = config.user,
-        password = password,
-        listen_port = port
+        upstream_port = session.upstream_port,
+        user = credentials.user,
+        extra_options = if config.read_only {
+            " connect_query='SET default_transaction_read_only = on'"
+        } else {
+            ""
+        },
+        listen_addr = session.listen_addr,
+        listen_port = session.port,
+        auth_file = session.auth_file.display(),
+        pid_file = session.pid_file.display()
     );
 
-    ini_file.write_all(ini_content.as_bytes())?;
+    util::write_private(
+        &session.auth_file,
+        &pgbouncer_auth_file(session, &credentials.user, password),
+    )?;
+    util::write_private(&session.ini, &ini_content)?;
+
+    Ok(())
+}
+
+/// Rewrites the ini and reloads pgbouncer after Vault handed out new
+/// credentials, so new server connections use them.
+fn reload_pgbouncer(
+    session: &PgbouncerSession,
+    config: &PostgresConfig,
+    credentials: &Credentials,
+) -> Result<()> {
+    write_pgbouncer_ini(session, config, credentials)?;
+
+    let pid = fs::read_to_string(&session.pid_file)?
+        .trim()
+        .parse::<u32>()
+        .map_err(|e| FigError::ParseError(format!("invalid pgbouncer pid file: {}", e)))?;
+    signal::send(pid, libc::SIGHUP, false)?;
+
+    Ok(())
+}
+
+fn postgres_pgbouncer_cmd(
+    config: &PostgresConfig,
+    credentials: &Credentials,
+    vault_session: Option<vault::Session>,
+    listen_addr: &str,
+    port: u16,
+    upstream_port: u16,
+) -> Result<Command> {
+    let session = PgbouncerSession::new(listen_addr, port, upstream_port)?;
+
+    write_pgbouncer_ini(&session, config, credentials)?;
+
+    if let Some(vault_session) = vault_session {
+        let session = session.clone();
+        let config = config.clone();
+        vault_session.keep_alive(credentials.user.clone(), move |credentials| {
+            println!("Vault issued new credentials, reloading pgbouncer");
+            if let Err(e) = reload_pgbouncer(&session, &config, credentials) {
+                eprintln!("Could not reload pgbouncer: {}", e);
+            }
+        });
+    }
 
     let mut cmd = Command::new("pgbouncer");
 
-    cmd.args(vec![ini_file_path_str.to_str().unwrap()]);
+    cmd.arg(&session.ini);
 
     let mut table = Table::new();
 
@@ -285,24 +795,185 @@ fn postgres_pgbouncer_cmd(
 
     table.add_row(row![
         "connection string",
-        format!("postgresql://localhost:{}/{}", port, config.database)
+        session.connection_string(&config.database)
     ]);
-    table.add_row(row!["host", "localhost"]);
+    table.add_row(row!["host", util::connect_host(&session.listen_addr)]);
     table.add_row(row!["port", port.to_string()]);
     table.add_row(row!["database", config.database]);
+    table.add_row(row!["user", session.user]);
+    table.add_row(row!["password", session.password]);
 
     table.printstd();
 
     Ok(cmd)
 }
 
+/// The credentials to connect with, fetching them from the configured
+/// `password_source` if there is one. The Vault session is returned so its
+/// lease can be kept alive for as long as the connection is in use.
+fn postgres_credentials(
+    config: &PostgresConfig,
+    vault_cache: &Path,
+) -> Result<(Credentials, Option<vault::Session>)> {
+    match &config.password_source {
+        Some(PasswordSource::Vault(source)) => {
+            let session = vault::Session::open(source, vault_cache)?;
+            Ok((session.credentials(&config.user), Some(session)))
+        }
+        Some(PasswordSource::Iam) => Ok((
+            Credentials {
+                user: config.user.clone(),
+                password: Some(cloudsql::login_token()?),
+            },
+            None,
+        )),
+        None => Ok((
+            Credentials {
+                user: config.user.clone(),
+                password: config.password.clone(),
+            },
+            None,
+        )),
+    }
+}
+
+/// Asks for confirmation when `env` is protected, then resolves the
+/// credentials and writes the audit log line for them. Nothing is started
+/// (or fetched from Vault) before the confirmation passes, and nothing is
+/// fetched at all for modes that don't log in themselves.
+#[allow(clippy::too_many_arguments)]
+fn postgres_guard(
+    postgres_config: &PostgresConfig,
+    config_name: &str,
+    env: &str,
+    mode: &PostgresMode,
+    confirmed: Option<&str>,
+    audit_log: &Path,
+    vault_cache: &Path,
+) -> Result<(Credentials, Option<vault::Session>)> {
+    let credentials = || {
+        if mode.logs_in() {
+            postgres_credentials(postgres_config, vault_cache)
+        } else {
+            Ok((
+                Credentials {
+                    user: postgres_config.user.clone(),
+                    password: None,
+                },
+                None,
+            ))
+        }
+    };
+    if !postgres_config.is_protected(env) {
+        return credentials();
+    }
+
+    guard::confirm(env, confirmed)?;
+    let (credentials, vault_session) = credentials()?;
+    guard::audit(
+        audit_log,
+        &guard::AuditEntry {
+            config: config_name,
+            env,
+            mode: mode.name(),
+            user: &credentials.user,
+            database: &postgres_config.database,
+            read_only: postgres_config.read_only && mode.logs_in(),
+        },
+    )?;
+
+    Ok((credentials, vault_session))
+}
+
+/// How `figcli psql` makes the database available.
+enum PostgresMode<'a> {
+    /// An interactive psql shell.
+    Shell,
+    /// A local pgbouncer listening on the address.
+    Pgbouncer(&'a str),
+    /// The built-in TCP relay listening on the address.
+    Relay(&'a str),
+    /// The built-in Postgres proxy listening on the address, logging clients
+    /// in with the configured credentials.
+    Proxy(&'a str),
+    /// Just the tunnel, on the local port.
+    PortForward,
+}
+
+impl PostgresMode<'_> {
+    fn name(&self) -> &'static str {
+        match self {
+            PostgresMode::Shell => "shell",
+            PostgresMode::Pgbouncer(_) => "pgbouncer",
+            PostgresMode::Relay(_) => "relay",
+            PostgresMode::Proxy(_) => "proxy",
+            PostgresMode::PortForward => "port-forward",
+        }
+    }
+
+    /// Whether figcli logs in to the database itself, which is what needs the
+    /// credentials and enforces `read_only`. The relay and the bare tunnel pass
+    /// client connections through untouched, clients log in on their own.
+    fn logs_in(&self) -> bool {
+        matches!(
+            self,
+            PostgresMode::Shell | PostgresMode::Pgbouncer(_) | PostgresMode::Proxy(_)
+        )
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
 fn postgres_cli_cmd(
     config: &Config,
+    config_name: &str,
     env: Option<&str>,
     port: Option<u16>,
-    interative_shell: bool,
-    use_pgbouncer: bool,
+    mode: PostgresMode,
+    ready_timeout: Duration,
+    use_kubectl: bool,
+    confirmed: Option<&str>,
+    audit_log: &Path,
+    vault_cache: &Path,
 ) -> Result<()> {
+    let postgres_config = &config.postgres(env)?;
+    let env = env.unwrap_or_default();
+    if let PostgresMode::Proxy(listen_addr) = mode {
+        if !util::is_loopback(listen_addr) {
+            return Err(FigError::ParseError(format!(
+                "--proxy logs in everyone who connects, it only listens on loopback addresses, not {} \
+                 (use --pgbouncer to share the database with other hosts)",
+                listen_addr
+            )));
+        }
+    }
+    if let ServerConfigType::GCloudProxy(CloudSqlConfig {
+        unix_socket: Some(_),
+        ..
+    }) = &postgres_config._type
+    {
+        if !matches!(mode, PostgresMode::Shell | PostgresMode::PortForward) {
+            return Err(FigError::ConfigError(format!(
+                "a unix_socket proxy can't be used with --{}",
+                mode.name()
+            )));
+        }
+    }
+    if postgres_config.read_only && !mode.logs_in() {
+        eprintln!(
+            "Warning: read_only is not enforced with {}, clients connect with the permissions of their own login",
+            mode.name()
+        );
+    }
+    let (credentials, vault_session) = postgres_guard(
+        postgres_config,
+        config_name,
+        env,
+        &mode,
+        confirmed,
+        audit_log,
+        vault_cache,
+    )?;
+
     let port = match port {
         Some(port) => port,
         None => {
@@ -312,57 +983,260 @@ fn postgres_cli_cmd(
         }
     };
 
-    let postgres_config =
-        match environment_type(env)? {
-            EnvironmentType::Local => config.postgres_local.as_ref().ok_or_else(|| {
-                FigError::ConfigError("[postgres_local] block is invalid".to_owned())
-            })?,
-            EnvironmentType::Test => config.postgres_test.as_ref().ok_or_else(|| {
-                FigError::ConfigError("[postgres_test] block is invalid".to_owned())
-            })?,
-            EnvironmentType::Production => config.postgres_prod.as_ref().ok_or_else(|| {
-                FigError::ConfigError("[postgres_prod] block is invalid".to_owned())
-            })?,
-        };
+    match mode {
+        PostgresMode::Shell => {
+            if let Some(session) = vault_session {
+                session.keep_alive(credentials.user.clone(), |_| {
+                    eprintln!(
+                        "\nVault issued new credentials, the current ones stop working once \
+                         revoked. Restart the session to use them."
+                    );
+                });
+            }
 
-    if interative_shell {
-        runner::run_command(
-            &mut postgres_shell_cmd(postgres_config, port),
-            postgres_tunnel_cmd(postgres_config, port)?.as_mut(),
-            false,
-        )
-    } else if use_pgbouncer {
-        let bridge_port = util::find_available_port()?;
-        println!(
-            "Using pgbouncer with random open port for bridge {}",
-            bridge_port
-        );
-        runner::run_command(
-            &mut postgres_pgbouncer_cmd(postgres_config, port, bridge_port)?,
-            postgres_tunnel_cmd(postgres_config, bridge_port)?.as_mut(),
-            false,
-        )
-    } else {
-        println!("Using default port forwarding");
-        let mut forward_command = postgres_tunnel_cmd(postgres_config, port)?
-            .expect("couldn't build port-forward command");
-        runner::run_command(&mut forward_command, None, false)
+            let readiness = postgres_tunnel_readiness(postgres_config, port, ready_timeout);
+            let mut tunnel =
+                postgres_tunnel(postgres_config, port, ready_timeout, use_kubectl, false)?;
+            runner::run_command(
+                &mut postgres_shell_cmd(postgres_config, &credentials, env, port),
+                tunnel.parent(&readiness),
+                false,
+            )
+        }
+        PostgresMode::Pgbouncer(listen_addr) => {
+            let bridge_port = util::find_available_port()?;
+            println!(
+                "Using pgbouncer with random open port for bridge {}",
+                bridge_port
+            );
+            let readiness = postgres_tunnel_readiness(postgres_config, bridge_port, ready_timeout);
+            let mut tunnel = postgres_tunnel(
+                postgres_config,
+                bridge_port,
+                ready_timeout,
+                use_kubectl,
+                true,
+            )?;
+            runner::run_command(
+                &mut postgres_pgbouncer_cmd(
+                    postgres_config,
+                    &credentials,
+                    vault_session,
+                    listen_addr,
+                    port,
+                    bridge_port,
+                )?,
+                tunnel.parent(&readiness),
+                false,
+            )
+        }
+        PostgresMode::Relay(listen_addr) => {
+            let bridge_port = util::find_available_port()?;
+
+            match postgres_tunnel(
+                postgres_config,
+                bridge_port,
+                ready_timeout,
+                use_kubectl,
+                true,
+            )? {
+                // nothing to tunnel, relay straight to the database
+                Tunnel::None => {
+                    let upstream = format!("{}:{}", postgres_config.host(), postgres_config.port());
+                    let relay = relay::spawn(listen_addr, port, upstream.clone(), ready_timeout)?;
+                    println!("Relaying {}:{} to {}", listen_addr, port, upstream);
+                    relay
+                        .join()
+                        .map_err(|_| FigError::IoError(io::Error::other("relay stopped")))
+                }
+                tunnel => {
+                    relay::spawn(
+                        listen_addr,
+                        port,
+                        format!("127.0.0.1:{}", bridge_port),
+                        ready_timeout,
+                    )?;
+                    println!(
+                        "Relaying {}:{} through the tunnel on port {}",
+                        listen_addr, port, bridge_port
+                    );
+                    tunnel.run()
+                }
+            }
+        }
+        PostgresMode::Proxy(listen_addr) => {
+            let bridge_port = util::find_available_port()?;
+            let tunnel = postgres_tunnel(
+                postgres_config,
+                bridge_port,
+                ready_timeout,
+                use_kubectl,
+                true,
+            )?;
+            let upstream_addr = match tunnel {
+                Tunnel::None => format!("{}:{}", postgres_config.host(), postgres_config.port()),
+                _ => format!("127.0.0.1:{}", bridge_port),
+            };
+
+            let credentials = Arc::new(Mutex::new(credentials));
+            if let Some(vault_session) = vault_session {
+                let shared = credentials.clone();
+                let user = postgres_config.user.clone();
+                vault_session.keep_alive(user, move |new_credentials| {
+                    println!("Vault issued new credentials, new connections use them");
+                    if let Ok(mut credentials) = shared.lock() {
+                        *credentials = new_credentials.clone();
+                    }
+                });
+            }
+            let upstream = pgproxy::Upstream {
+                credentials,
+                database: postgres_config.database.clone(),
+                options: Some(postgres_options(postgres_config)),
+            };
+            let proxy = relay::spawn_with_handshake(
+                listen_addr,
+                port,
+                upstream_addr,
+                ready_timeout,
+                Arc::new(move |client: &mut TcpStream, server: &mut TcpStream| {
+                    pgproxy::handshake(&upstream, client, server)
+                }),
+            )?;
+
+            let mut table = Table::new();
+            table.set_format(*FORMAT_NO_BORDER_LINE_SEPARATOR);
+            table.set_titles(row!["KEY", "VALUE"]);
+            table.add_row(row![
+                "connection string",
+                format!(
+                    "postgresql://{}:{}/{}",
+                    util::url_host(util::connect_host(listen_addr)),
+                    port,
+                    postgres_config.database
+                )
+            ]);
+            table.add_row(row!["host", util::connect_host(listen_addr)]);
+            table.add_row(row!["port", port.to_string()]);
+            table.add_row(row!["database", postgres_config.database]);
+            table.printstd();
+
+            match tunnel {
+                Tunnel::None => proxy
+                    .join()
+                    .map_err(|_| FigError::IoError(io::Error::other("proxy stopped"))),
+                tunnel => tunnel.run(),
+            }
+        }
+        PostgresMode::PortForward => {
+            println!("Using default port forwarding");
+            match postgres_tunnel(postgres_config, port, ready_timeout, use_kubectl, true)? {
+                Tunnel::None => Err(FigError::ConfigError(
+                    "a direct connection has nothing to port-forward".to_owned(),
+                )),
+                tunnel => tunnel.run(),
+            }
+        }
     }
 }
 
+fn exec_cmd(config: &Config, env: Option<&str>, cmd: Option<Vec<&str>>) -> Result<()> {
+    let exec_config = &config.exec(env)?;
+    let kubernetes = &exec_config.kubernetes;
+
+    let pod = k8s::ready_pod(&k8s::Kubectl(kubernetes), &kubernetes.target()?)?;
+    // stderr, so the output of one-shot commands can be piped cleanly
+    eprintln!("Using pod {}", pod);
+
+    let mut kubectl = k8s::kubectl(kubernetes);
+    kubectl.args(vec!["exec", "-i"]);
+    if signal::stdin_is_tty() {
+        kubectl.arg("-t");
+    }
+    if let Some(container) = &kubernetes.container {
+        kubectl.args(vec!["-c", container]);
+    }
+    kubectl.args(vec![&pod, "--"]);
+    match cmd {
+        Some(cmd) => kubectl.args(cmd),
+        None => kubectl.args(exec_config.cmd()?),
+    };
+
+    runner::run_command(&mut kubectl, None, false)
+}
+
+fn logs_cmd(
+    config: &Config,
+    env: Option<&str>,
+    follow: bool,
+    since: Option<&str>,
+    container: Option<&str>,
+    grep: Option<&str>,
+) -> Result<()> {
+    let log_config = &config.logs(env)?;
+    let kubernetes = &log_config.kubernetes;
+    let follow = follow || log_config.follow;
+    let since = since.or(log_config.since.as_deref());
+    let container = container.or(kubernetes.container.as_deref());
+    let grep = grep
+        .map(Regex::new)
+        .transpose()
+        .map_err(|e| FigError::ParseError(format!("Could not parse grep pattern: {}", e)))?;
+
+    let target = kubernetes.target()?;
+    let pods = k8s::pods(&k8s::Kubectl(kubernetes), &target)?;
+    if pods.is_empty() {
+        return Err(FigError::ConfigError(format!("{} has no pods", target)));
+    }
+
+    let commands = pods
+        .into_iter()
+        .map(|pod| {
+            let mut kubectl = k8s::kubectl(kubernetes);
+            kubectl.args(vec!["logs", &pod]);
+            if let Some(container) = container {
+                kubectl.args(vec!["-c", container]);
+            }
+            if let Some(since) = since {
+                kubectl.arg(format!("--since={}", since));
+            }
+            if follow {
+                kubectl.arg("--follow");
+            }
+            (pod, kubectl)
+        })
+        .collect();
+
+    runner::run_merged(commands, move |line| {
+        grep.as_ref().is_none_or(|re| re.is_match(line))
+    })
+}
+
 fn doctor_cmd(cmd: &str, args: Vec<&str>) -> Result<()> {
     let mut runnable = Command::new(cmd);
     runnable.args(args);
 
     run_command(&mut runnable, None, true)
         .map(|_| println!("[*] {} is installed", cmd))
-        .map_err(|e| {
-            println!("[ ] {} is not installed", cmd);
-            e
-        })
+        .inspect_err(|_| println!("[ ] {} is not installed", cmd))
 }
 
+/// Writes a configuration file: a copy of another one with `from`, the
+/// database settings found in the current project, or a stub with examples
+/// if there are none.
 fn config_init_cmd<P: AsRef<Path>>(path: P, force: bool, from: Option<(P, P)>) -> Result<()> {
+    if from.is_none() {
+        let project_dir = env::current_dir()?;
+        let findings = scan::scan(&project_dir)?;
+        if !findings.is_empty() {
+            return config_init_scanned(path, force, &project_dir, &findings);
+        }
+        println!(
+            "Found no database settings in {}, installing an example configuration",
+            project_dir.display()
+        );
+    }
+
     let write_file = if force {
         true
     } else {
@@ -415,9 +1289,60 @@ fn config_init_cmd<P: AsRef<Path>>(path: P, force: bool, from: Option<(P, P)>) -
     Ok(())
 }
 
+/// Proposes a configuration file built from the database settings `findings`
+/// of `project_dir`, showing the changes before writing.
+fn config_init_scanned<P: AsRef<Path>>(
+    path: P,
+    force: bool,
+    project_dir: &Path,
+    findings: &scan::Findings,
+) -> Result<()> {
+    println!("Found database settings in:");
+    for source in findings.sources.iter() {
+        println!("  {}", source.strip_prefix(project_dir)?.display());
+    }
+
+    let blocks = scan::blocks(findings);
+    let current = if path.as_ref().exists() {
+        Some(fs::read_to_string(path.as_ref())?)
+    } else {
+        None
+    };
+    let proposed = match &current {
+        Some(current) => {
+            let (merged, kept) = scan::merge(current, &blocks)?;
+            for name in kept {
+                println!("Keeping the existing [{}] block", name);
+            }
+            merged
+        }
+        None => scan::render(&blocks),
+    };
+    let current = current.unwrap_or_default();
+
+    if current == proposed {
+        println!("\n{} is already up to date", path.as_ref().display());
+        return Ok(());
+    }
+
+    println!("\nProposed changes to {}:\n", path.as_ref().display());
+    for line in util::line_diff(&secret::redact(&current)?, &secret::redact(&proposed)?) {
+        println!("{}", line);
+    }
+
+    if !force && !util::prompt_yes_no(&format!("Write {}", path.as_ref().display())) {
+        return Ok(());
+    }
+
+    println!("Writing config file to {}", path.as_ref().display());
+    util::write_private(path.as_ref(), &proposed)?;
+
+    Ok(())
+}
+
 fn config_show_contents<P: AsRef<Path>>(path: P) -> Result<()> {
     let contents = fs::read_to_string(path.as_ref())?;
-    println!("{}", contents);
+    print!("{}", secret::redact(&contents)?);
     Ok(())
 }
 
@@ -453,6 +1378,48 @@ fn config_list_files<P: AsRef<Path>>(path: P) -> Result<()> {
     Ok(())
 }
 
+/// The value of the global `--config` argument, read ahead of clap.
+fn config_name_arg() -> String {
+    let mut args = env::args().skip(1);
+
+    while let Some(arg) = args.next() {
+        if arg == "--" {
+            break;
+        } else if arg == "-c" || arg == "--config" {
+            if let Some(name) = args.next() {
+                return name;
+            }
+        } else if let Some(name) = arg.strip_prefix("--config=") {
+            return name.to_owned();
+        } else if let Some(name) = arg.strip_prefix("-c").filter(|n| !n.is_empty()) {
+            return name.to_owned();
+        }
+    }
+
+    "default".to_owned()
+}
+
+/// The positional environment argument, limited to the environments `envs`
+/// configured for the subcommand. Without a (readable) configuration file
+/// any name is accepted, so the missing file or the syntax error in it can
+/// be reported properly.
+fn env_arg<'a, 'b>(envs: &'b [&'b str]) -> Arg<'a, 'b> {
+    let arg = Arg::with_name("environment")
+        .required(true)
+        .index(1)
+        .short("e")
+        .long("environment")
+        .value_name("ENV")
+        .takes_value(true)
+        .help("Environment to apply SUBCOMMAND to.");
+
+    if envs.is_empty() {
+        arg
+    } else {
+        arg.possible_values(envs)
+    }
+}
+
 fn get_config_paths() -> Result<(PathBuf, PathBuf)> {
     let mut default_config_path = dirs::config_dir().unwrap();
     default_config_path.push(FIG_CONFIG_DIR);
@@ -470,7 +1437,27 @@ fn get_config_paths() -> Result<(PathBuf, PathBuf)> {
     Ok((default_config_path, base_config_path))
 }
 
-fn main() -> Result<()> {
+fn main() {
+    let code = {
+        // dropped before exiting, `process::exit` doesn't run destructors
+        let _temp_files = util::TempFileCleanup;
+
+        match run() {
+            Ok(()) => 0,
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                if let Some(hint) = e.hint() {
+                    eprintln!("Hint: {}", hint);
+                }
+                e.exit_code()
+            }
+        }
+    };
+
+    std::process::exit(code);
+}
+
+fn run() -> Result<()> {
     let (default_config_path, base_config_path) = {
         let mut default_config_path = dirs::config_dir().unwrap();
         default_config_path.push(FIG_CONFIG_DIR);
@@ -504,15 +1491,46 @@ fn main() -> Result<()> {
         .value_name("PGBOUNCER")
         .takes_value(false)
         .help("Enable pgbouncer usage for tunnelling. If not provided with kubectl or some other method. Not compatible with --shell");
-    let env_arg = Arg::with_name("environment")
-        .required(true)
-        .index(1)
-        .short("e")
-        .long("environment")
-        .value_name("ENV")
+    let listen_addr_arg = Arg::with_name("listen-addr")
+        .long("listen-addr")
+        .value_name("ADDR")
         .takes_value(true)
-        .possible_values(&["local", "test", "prod"])
-        .help("Environment to apply SUBCOMMAND to.");
+        .default_value("127.0.0.1")
+        .help("Address pgbouncer, the relay or the proxy listens on. Use 0.0.0.0 to accept connections from other hosts.");
+    let relay_arg = Arg::with_name("relay")
+        .long("relay")
+        .takes_value(false)
+        .conflicts_with_all(&["shell", "pgbouncer"])
+        .help(
+            "Serve the port with a built-in TCP relay that stays up while the tunnel reconnects.",
+        );
+    let proxy_arg = Arg::with_name("proxy")
+        .long("proxy")
+        .takes_value(false)
+        .conflicts_with_all(&["shell", "pgbouncer", "relay"])
+        .help("Serve the port with a built-in Postgres proxy that logs clients in with the configured credentials.");
+    let kubectl_arg = Arg::with_name("kubectl")
+        .long("kubectl")
+        .takes_value(false)
+        .help("Port-forward with kubectl instead of talking to the Kubernetes API directly.");
+    let ready_timeout_arg = Arg::with_name("ready-timeout")
+        .long("ready-timeout")
+        .value_name("SECONDS")
+        .takes_value(true)
+        .default_value("30")
+        .help("How long to wait for the tunnel to accept connections before giving up.");
+    // environments come from the configuration file, so it has to be read
+    // before the arguments can be parsed
+    let mut preload_config_path = default_config_path.clone();
+    preload_config_path.push(config_name_arg());
+    preload_config_path.set_extension("toml");
+    let preloaded_config = get_config(preload_config_path).ok();
+    let environments = |envs: fn(&Config) -> Vec<&str>| -> Vec<&str> {
+        preloaded_config.as_ref().map(envs).unwrap_or_default()
+    };
+    let postgres_envs = environments(|c| c.postgres.keys().map(String::as_str).collect());
+    let exec_envs = environments(|c| c.exec.keys().map(String::as_str).collect());
+    let logs_envs = environments(|c| c.logs.keys().map(String::as_str).collect());
     let config_arg = Arg::with_name("config")
         .required(false)
         .global(true)
@@ -546,14 +1564,18 @@ fn main() -> Result<()> {
             )
             .subcommand(SubCommand::with_name(INIT)
                 .arg(&force_arg)
-                .arg(&Arg::with_name("from")
+                .arg(Arg::with_name("from")
                     .required(false)
                     .long("from")
                     .value_name("FILE")
                     .takes_value(true)
                     .help("Copy an existing configuration file")
                 )
-                .about("Installs a stub configuration file with examples to help with setup")
+                .about("Generates a configuration file by scanning the current project")
+                .long_about("Generates a configuration file by scanning the current project for Kubernetes \
+                             manifests, Helm values, docker-compose files and application configuration \
+                             (application.yml, .env, ...). The proposed changes are shown before writing. \
+                             If nothing is found, a stub configuration file with examples is installed.")
             )
             .subcommand(SubCommand::with_name(PATH)
                 .about("Prints the location of the configuration file that will be used")
@@ -562,7 +1584,7 @@ fn main() -> Result<()> {
                 .about("Prints the contents of the configuration file that will be used")
             )
             .subcommand(SubCommand::with_name(LIST)
-                .arg(&Arg::with_name("all")
+                .arg(Arg::with_name("all")
                     .required(false)
                     .long("all")
                     .short("A")
@@ -573,23 +1595,31 @@ fn main() -> Result<()> {
             )
         )
         .subcommand(SubCommand::with_name(PORT_FORWARD)
-            .arg(&Arg::with_name("forward")
+            .arg(Arg::with_name("forward")
                  .value_name("SPECIFIER")
-                 .required(true)
+                 .required_unless("all")
                  .takes_value(true)
-                 .help("Forwarding specifier string, like the ssh -L option")
-                 .long_help("The forwarding string is meant to function like ssh's -L option:\n\n\
+                 .multiple(true)
+                 .help("Forwarding specifier strings, like the ssh -L option, or target names")
+                 .long_help("The forwarding strings are meant to function like ssh's -L option:\n\n\
                               - <remote-host>:<remote-port>\n- <local-port>:<remote-host>:<remote-port>\n\n\
-                              If <local-port> is omitted, a random port will be chosen\n")
+                              If <local-port> is omitted, a random port will be chosen. Several specifiers \
+                              can be given, hosts are all forwarded through the same relay pod. A remote \
+                              of svc/<name>, pod/<name> or deploy/<name> is forwarded to directly. A name \
+                              forwards the [port_forward.targets.<name>] block of the configuration.\n")
             )
-            .arg(&Arg::with_name("context")
+            .arg(Arg::with_name("all")
+                 .long("all")
+                 .help("Forwards every target in the configuration")
+            )
+            .arg(Arg::with_name("context")
                  .required(false)
                  .value_name("NAME")
                  .long("context")
                  .takes_value(true)
                  .help("The Kubernetes context to use. Overrides the one provided in config")
             )
-            .arg(&Arg::with_name("namespace")
+            .arg(Arg::with_name("namespace")
                  .required(false)
                  .value_name("NAME")
                  .long("namespace")
@@ -600,14 +1630,64 @@ fn main() -> Result<()> {
             .about("Perform port forwarding within a Kubernetes cluster")
         )
         .subcommand(SubCommand::with_name(POSTGRES_CLI)
-            .arg(&env_arg)
+            .arg(env_arg(&postgres_envs))
             .arg(&static_port_arg)
             .arg(&interactive_shell_arg)
             .arg(&pgbouncer_arg)
+            .arg(&relay_arg)
+            .arg(&proxy_arg)
+            .arg(&listen_addr_arg)
+            .arg(&kubectl_arg)
+            .arg(&ready_timeout_arg)
+            .arg(Arg::with_name("confirm")
+                 .long("confirm")
+                 .value_name("ENV")
+                 .takes_value(true)
+                 .help("Confirms connecting to a protected environment without being asked, must match ENV")
+            )
             .about("Proxies a remote postgres connection")
         )
+        .subcommand(SubCommand::with_name(EXEC)
+            .arg(env_arg(&exec_envs))
+            .arg(Arg::with_name("cmd")
+                 .value_name("CMD")
+                 .multiple(true)
+                 .last(true)
+                 .help("Command to run instead of the configured one, e.g. `-- ls -la`")
+            )
+            .about("Runs a command inside a ready pod of the configured Kubernetes workload")
+        )
+        .subcommand(SubCommand::with_name(LOGS)
+            .arg(env_arg(&logs_envs))
+            .arg(Arg::with_name("follow")
+                 .long("follow")
+                 .short("f")
+                 .takes_value(false)
+                 .help("Keep streaming new log lines")
+            )
+            .arg(Arg::with_name("since")
+                 .long("since")
+                 .value_name("DURATION")
+                 .takes_value(true)
+                 .help("Only show logs newer than a relative duration like 5s, 2m or 3h")
+            )
+            .arg(Arg::with_name("container")
+                 .long("container")
+                 .value_name("NAME")
+                 .takes_value(true)
+                 .help("The container to show logs for. Overrides the one provided in config")
+            )
+            .arg(Arg::with_name("grep")
+                 .long("grep")
+                 .short("g")
+                 .value_name("PATTERN")
+                 .takes_value(true)
+                 .help("Only show lines matching this regular expression")
+            )
+            .about("Shows the logs of all pods of the configured Kubernetes workload")
+        )
         .subcommand(SubCommand::with_name(KONG_API_KEY)
-            .arg(&Arg::with_name("name")
+            .arg(Arg::with_name("name")
                  .required(true)
                  .value_name("NAME")
                  .long("name")
@@ -618,7 +1698,7 @@ fn main() -> Result<()> {
 include both the caller and what system they are calling, i.e.
 "third-party-to-service-identity"."#)
             )
-            .arg(&Arg::with_name("namespace")
+            .arg(Arg::with_name("namespace")
                  .required(true)
                  .value_name("NAMESPACE")
                  .long("namespace")
@@ -626,7 +1706,7 @@ include both the caller and what system they are calling, i.e.
                  .takes_value(true)
                  .help("The kubernetes namespace to use.")
             )
-            .arg(&Arg::with_name("acl-group")
+            .arg(Arg::with_name("acl-group")
                  .required(true)
                  .value_name("ACL")
                  .long("acl-group")
@@ -634,7 +1714,7 @@ include both the caller and what system they are calling, i.e.
                  .takes_value(true)
                  .help("The acl group name to use.")
             )
-            .arg(&Arg::with_name("uuid")
+            .arg(Arg::with_name("uuid")
                  .required(false)
                  .value_name("UUID")
                  .long("uuid")
@@ -655,12 +1735,19 @@ include both the caller and what system they are calling, i.e.
 
     match args.subcommand() {
         (DOCTOR, _) => {
-            let commands = vec![
+            let commands = [
                 doctor_cmd("kubectl", vec![""]),
                 doctor_cmd("psql", vec!["--version"]),
                 doctor_cmd("gcloud", vec!["version"]),
-                doctor_cmd("pgbouncer", vec!["--version"]),
             ];
+            // only needed for `psql --pgbouncer`, `--relay` works without it
+            if doctor_cmd("pgbouncer", vec!["--version"]).is_err() {
+                println!("    optional, only needed for `figcli psql --pgbouncer`");
+            }
+            let cloudsql_proxy = cloudsql::Proxy::detect().unwrap_or(cloudsql::Proxy::V2);
+            if doctor_cmd(cloudsql_proxy.binary(), vec![cloudsql_proxy.version_arg()]).is_err() {
+                println!("    optional, only needed for `gcloudproxy` environments");
+            }
 
             if commands.iter().any(|res| res.is_err()) {
                 return Err(FigError::DoctorError(
@@ -721,27 +1808,67 @@ include both the caller and what system they are calling, i.e.
             config_path.push(args.value_of("config").unwrap());
             config_path.set_extension("toml");
 
-            let port_forward_config = get_config(config_path).ok().and_then(|c| c.port_forward);
-            let forward_value = values
-                .value_of("forward")
-                .ok_or_else(|| FigError::ParseError("Could not parse remote string".to_owned()))?;
-            let forwarding = util::parse_forwarding_string(forward_value)?;
+            // plain forwarding strings work without a configuration file
+            let port_forward_config = match get_config(config_path) {
+                Ok(config) => config.port_forward()?,
+                Err(FigError::ConfigNotFound(_)) => None,
+                Err(e) => return Err(e),
+            };
+            let targets = port_forward_config
+                .as_ref()
+                .map(|config| config.targets.clone())
+                .unwrap_or_default();
+            let mut names = if values.is_present("all") {
+                if targets.is_empty() {
+                    return Err(FigError::ConfigError(
+                        "no port_forward targets are configured".to_owned(),
+                    ));
+                }
+                targets.keys().cloned().collect::<Vec<_>>()
+            } else {
+                Vec::new()
+            };
+            let mut forwardings = Vec::new();
+            for value in values.values_of("forward").into_iter().flatten() {
+                // anything that isn't a forwarding string names a target
+                if value.contains(':') {
+                    forwardings.push(util::parse_forwarding_string(value).map_err(|e| {
+                        FigError::ParseError(format!("invalid forwarding {}: {}", value, e))
+                    })?);
+                } else if !targets.contains_key(value) {
+                    return Err(FigError::ParseError(format!(
+                        "no port_forward target named {}, configured are: {}",
+                        value,
+                        if targets.is_empty() {
+                            "none".to_owned()
+                        } else {
+                            targets.keys().cloned().collect::<Vec<_>>().join(", ")
+                        }
+                    )));
+                } else if !names.iter().any(|name| name == value) {
+                    names.push(value.to_owned());
+                }
+            }
+            let forwardings = names
+                .iter()
+                .map(|name| targets[name].forwarding(name))
+                .chain(forwardings.into_iter().map(Ok))
+                .collect::<Result<Vec<_>>>()?;
             let context = values.value_of("context");
             let namespace = values.value_of("namespace");
 
             k8s_port_forward(
                 port_forward_config.as_ref(),
-                &forwarding,
+                &forwardings,
                 context,
                 namespace,
             )?
         }
         (POSTGRES_CLI, Some(values)) => {
-            let (mut config_path, _) = get_config_paths()?;
+            let (mut config_path, base_config_path) = get_config_paths()?;
             config_path.push(args.value_of("config").unwrap());
             config_path.set_extension("toml");
 
-            // TODO on this error make sure printed messages shows you how to create a config file
             let config = get_config(config_path)?;
             let port = match value_t!(values.value_of("port"), u16) {
                 Ok(port) => Ok(Some(port)),
@@ -754,15 +1881,54 @@ include both the caller and what system they are calling, i.e.
                     _ => unreachable!(),
                 },
             }?;
-            let interactive_shell = values.is_present("shell");
-            let use_pgbouncer = values.is_present("pgbouncer");
+            let listen_addr = values.value_of("listen-addr").unwrap();
+            let mode = if values.is_present("shell") {
+                PostgresMode::Shell
+            } else if values.is_present("pgbouncer") {
+                PostgresMode::Pgbouncer(listen_addr)
+            } else if values.is_present("relay") {
+                PostgresMode::Relay(listen_addr)
+            } else if values.is_present("proxy") {
+                PostgresMode::Proxy(listen_addr)
+            } else {
+                PostgresMode::PortForward
+            };
+            let ready_timeout = value_t!(values.value_of("ready-timeout"), u64).map_err(|_| {
+                FigError::ParseError("Could not parse ready-timeout to u64.".to_owned())
+            })?;
 
             postgres_cli_cmd(
                 &config,
+                args.value_of("config").unwrap(),
                 values.value_of("environment"),
                 port,
-                interactive_shell,
-                use_pgbouncer,
+                mode,
+                Duration::from_secs(ready_timeout),
+                values.is_present("kubectl"),
+                values.value_of("confirm"),
+                &base_config_path.join(AUDIT_LOG),
+                &base_config_path.join(VAULT_CACHE_DIR),
+            )?
+        }
+        (EXEC, Some(values)) => {
+            let config = get_config(config_path)?;
+
+            exec_cmd(
+                &config,
+                values.value_of("environment"),
+                values.values_of("cmd").map(|v| v.collect()),
+            )?
+        }
+        (LOGS, Some(values)) => {
+            let config = get_config(config_path)?;
+
+            logs_cmd(
+                &config,
+                values.value_of("environment"),
+                values.is_present("follow"),
+                values.value_of("since"),
+                values.value_of("container"),
+                values.value_of("grep"),
             )?
         }
         (KONG_API_KEY, Some(values)) => {
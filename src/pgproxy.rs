@@ -0,0 +1,585 @@
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::sync::{Arc, Mutex};
+
+use crate::config::Credentials;
+use crate::scram;
+
+const PROTOCOL_VERSION_3: u32 = 196_608;
+const CANCEL_REQUEST: u32 = 80_877_102;
+const SSL_REQUEST: u32 = 80_877_103;
+const GSSENC_REQUEST: u32 = 80_877_104;
+
+const AUTH_OK: u32 = 0;
+const AUTH_CLEARTEXT: u32 = 3;
+const AUTH_MD5: u32 = 5;
+const AUTH_SASL: u32 = 10;
+const AUTH_SASL_CONTINUE: u32 = 11;
+const AUTH_SASL_FINAL: u32 = 12;
+
+const SCRAM_SHA_256: &str = "SCRAM-SHA-256";
+
+/// What a proxied connection logs in to the server with. The credentials
+/// are shared so they can be swapped while the proxy runs, e.g. when Vault
+/// hands out new ones; connections that are already open keep theirs.
+pub struct Upstream {
+    pub credentials: Arc<Mutex<Credentials>>,
+    /// Used when the client doesn't ask for a database.
+    pub database: String,
+    /// Added to the `options` startup parameter of every connection.
+    pub options: Option<String>,
+}
+
+enum Startup {
+    Params(Vec<(String, String)>),
+    Cancel(Vec<u8>),
+}
+
+/// The Postgres side of the proxy, run by the relay on every connection:
+/// accepts the client without authentication, logs in to the server with
+/// the configured credentials and tells the client it's in. Everything from
+/// there on (parameter status, ready for query, ...) is relayed unchanged.
+pub fn handshake(
+    upstream: &Upstream,
+    client: &mut TcpStream,
+    server: &mut TcpStream,
+) -> io::Result<()> {
+    let params = match read_startup(client)? {
+        Startup::Params(params) => params,
+        // cancel keys come from the server, they can be passed on as they are
+        Startup::Cancel(request) => return server.write_all(&request),
+    };
+    let credentials = upstream
+        .credentials
+        .lock()
+        .map_err(|_| io::Error::other("credentials lock poisoned"))?
+        .clone();
+
+    let mut startup = PROTOCOL_VERSION_3.to_be_bytes().to_vec();
+    let mut has_user = false;
+    let mut has_database = false;
+    let mut has_options = false;
+    for (name, value) in &params {
+        let value = match name.as_str() {
+            // whoever the client says it is, it logs in as the configured user
+            "user" => {
+                has_user = true;
+                credentials.user.clone()
+            }
+            "database" => {
+                has_database = true;
+                value.clone()
+            }
+            "options" => {
+                has_options = true;
+                match &upstream.options {
+                    Some(options) => format!("{} {}", value, options),
+                    None => value.clone(),
+                }
+            }
+            _ => value.clone(),
+        };
+        push_cstr(&mut startup, name);
+        push_cstr(&mut startup, &value);
+    }
+    if !has_user {
+        push_cstr(&mut startup, "user");
+        push_cstr(&mut startup, &credentials.user);
+    }
+    if !has_database {
+        push_cstr(&mut startup, "database");
+        push_cstr(&mut startup, &upstream.database);
+    }
+    if let (false, Some(options)) = (has_options, &upstream.options) {
+        push_cstr(&mut startup, "options");
+        push_cstr(&mut startup, options);
+    }
+    startup.push(0);
+    server.write_all(&with_length(None, &startup))?;
+
+    // the proxy talks to the server in plain text, which is only private
+    // through a tunnel (or to a server on this host)
+    let private = server.peer_addr()?.ip().is_loopback();
+    if let Err(e) = authenticate(server, &credentials, private) {
+        let _ = client.write_all(&error_response(&e.to_string()));
+        return Err(e);
+    }
+
+    client.write_all(&with_length(Some(b'R'), &AUTH_OK.to_be_bytes()))
+}
+
+/// Reads the client's startup message, turning down SSL and GSS encryption
+/// (the proxy only listens locally) until the client sends it in plain text.
+fn read_startup(client: &mut TcpStream) -> io::Result<Startup> {
+    loop {
+        let len = read_u32(client)? as usize;
+        if !(8..=10_000).contains(&len) {
+            return Err(protocol_error("invalid startup message length"));
+        }
+        let mut body = vec![0; len - 4];
+        client.read_exact(&mut body)?;
+
+        match u32::from_be_bytes([body[0], body[1], body[2], body[3]]) {
+            SSL_REQUEST | GSSENC_REQUEST => client.write_all(b"N")?,
+            CANCEL_REQUEST => return Ok(Startup::Cancel(with_length(None, &body))),
+            PROTOCOL_VERSION_3 => {
+                let fields = body[4..]
+                    .split(|b| *b == 0)
+                    .map(|field| String::from_utf8_lossy(field).into_owned())
+                    .collect::<Vec<_>>();
+                let params = fields
+                    .chunks_exact(2)
+                    .filter(|pair| !pair[0].is_empty())
+                    .map(|pair| (pair[0].clone(), pair[1].clone()))
+                    .collect();
+
+                return Ok(Startup::Params(params));
+            }
+            version => {
+                return Err(protocol_error(&format!(
+                    "unsupported protocol version {}",
+                    version
+                )))
+            }
+        }
+    }
+}
+
+/// Answers the server's authentication requests until it accepts the
+/// credentials. Unless the connection is `private`, only SCRAM is used: a
+/// cleartext password would be readable on the network and an md5 hash is
+/// as good as the password to whoever captures it.
+fn authenticate(
+    server: &mut TcpStream,
+    credentials: &Credentials,
+    private: bool,
+) -> io::Result<()> {
+    let password = || {
+        credentials
+            .password
+            .as_deref()
+            .ok_or_else(|| io::Error::other("the server wants a password, none is configured"))
+    };
+    let plain_password = |method: &str| {
+        if private {
+            password()
+        } else {
+            Err(io::Error::other(format!(
+                "the server asks for a {} password over an unencrypted connection, only SCRAM is used \
+                 for servers that aren't reached through a tunnel",
+                method
+            )))
+        }
+    };
+    let mut scram: Option<scram::Client> = None;
+
+    loop {
+        let (tag, body) = read_message(server)?;
+        match tag {
+            b'R' if body.len() >= 4 => {
+                let data = &body[4..];
+                match u32::from_be_bytes([body[0], body[1], body[2], body[3]]) {
+                    AUTH_OK => return Ok(()),
+                    AUTH_CLEARTEXT => {
+                        let mut message = plain_password("cleartext")?.as_bytes().to_vec();
+                        message.push(0);
+                        server.write_all(&with_length(Some(b'p'), &message))?;
+                    }
+                    AUTH_MD5 => {
+                        let password = plain_password("md5")?;
+                        let inner = md5::compute(format!("{}{}", password, credentials.user));
+                        let mut outer = format!("{:x}", inner).into_bytes();
+                        outer.extend_from_slice(data);
+                        let mut message = format!("md5{:x}", md5::compute(outer)).into_bytes();
+                        message.push(0);
+                        server.write_all(&with_length(Some(b'p'), &message))?;
+                    }
+                    AUTH_SASL => {
+                        let mechanisms = data
+                            .split(|b| *b == 0)
+                            .map(String::from_utf8_lossy)
+                            .collect::<Vec<_>>();
+                        if !mechanisms.iter().any(|m| m == SCRAM_SHA_256) {
+                            return Err(io::Error::other(format!(
+                                "unsupported SASL mechanisms {}",
+                                mechanisms.join(" ")
+                            )));
+                        }
+
+                        let client_scram = scram::Client::new(password()?);
+                        let first = client_scram.client_first();
+                        let mut message = Vec::new();
+                        push_cstr(&mut message, SCRAM_SHA_256);
+                        message.extend_from_slice(&(first.len() as u32).to_be_bytes());
+                        message.extend_from_slice(first.as_bytes());
+                        server.write_all(&with_length(Some(b'p'), &message))?;
+                        scram = Some(client_scram);
+                    }
+                    AUTH_SASL_CONTINUE => {
+                        let client_scram = scram
+                            .as_mut()
+                            .ok_or_else(|| protocol_error("SASL continue before SASL start"))?;
+                        let last = client_scram
+                            .client_final(&String::from_utf8_lossy(data))
+                            .map_err(io::Error::other)?;
+                        server.write_all(&with_length(Some(b'p'), last.as_bytes()))?;
+                    }
+                    AUTH_SASL_FINAL => {
+                        scram
+                            .as_ref()
+                            .ok_or_else(|| protocol_error("SASL final before SASL start"))?
+                            .verify(&String::from_utf8_lossy(data))
+                            .map_err(io::Error::other)?;
+                    }
+                    method => {
+                        return Err(io::Error::other(format!(
+                            "unsupported authentication method {}",
+                            method
+                        )))
+                    }
+                }
+            }
+            b'E' => {
+                return Err(io::Error::other(format!(
+                    "server refused the login: {}",
+                    error_message(&body)
+                )));
+            }
+            tag => {
+                return Err(protocol_error(&format!(
+                    "unexpected message {:?} during authentication",
+                    tag as char
+                )))
+            }
+        }
+    }
+}
+
+fn read_u32(stream: &mut TcpStream) -> io::Result<u32> {
+    let mut bytes = [0; 4];
+    stream.read_exact(&mut bytes)?;
+
+    Ok(u32::from_be_bytes(bytes))
+}
+
+fn read_message(stream: &mut TcpStream) -> io::Result<(u8, Vec<u8>)> {
+    let mut tag = [0; 1];
+    stream.read_exact(&mut tag)?;
+    let len = read_u32(stream)? as usize;
+    if !(4..=1_000_000).contains(&len) {
+        return Err(protocol_error("invalid message length"));
+    }
+    let mut body = vec![0; len - 4];
+    stream.read_exact(&mut body)?;
+
+    Ok((tag[0], body))
+}
+
+/// A message with its length prefix, and its type byte if it has one (only
+/// startup messages don't).
+fn with_length(tag: Option<u8>, body: &[u8]) -> Vec<u8> {
+    let mut message = Vec::with_capacity(body.len() + 5);
+    message.extend(tag);
+    message.extend_from_slice(&((body.len() + 4) as u32).to_be_bytes());
+    message.extend_from_slice(body);
+
+    message
+}
+
+fn push_cstr(buffer: &mut Vec<u8>, s: &str) {
+    buffer.extend_from_slice(s.as_bytes());
+    buffer.push(0);
+}
+
+/// A FATAL `ErrorResponse` for errors of the proxy itself.
+fn error_response(message: &str) -> Vec<u8> {
+    let mut body = Vec::new();
+    for (field, value) in [
+        (b'S', "FATAL"),
+        (b'V', "FATAL"),
+        (b'C', "08006"),
+        (b'M', &format!("figcli: {}", message)),
+    ] {
+        body.push(field);
+        push_cstr(&mut body, value);
+    }
+    body.push(0);
+
+    with_length(Some(b'E'), &body)
+}
+
+/// The `M`essage field of an `ErrorResponse`.
+fn error_message(body: &[u8]) -> String {
+    body.split(|b| *b == 0)
+        .find_map(|field| field.strip_prefix(b"M"))
+        .map(|message| String::from_utf8_lossy(message).into_owned())
+        .unwrap_or_default()
+}
+
+fn protocol_error(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::thread;
+
+    /// Both ends of a local TCP connection.
+    fn pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let near = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (far, _) = listener.accept().unwrap();
+
+        (near, far)
+    }
+
+    fn credentials(password: Option<&str>) -> Credentials {
+        Credentials {
+            user: "app".to_owned(),
+            password: password.map(ToOwned::to_owned),
+        }
+    }
+
+    fn auth_request(method: u32, data: &[u8]) -> Vec<u8> {
+        let mut body = method.to_be_bytes().to_vec();
+        body.extend_from_slice(data);
+        with_length(Some(b'R'), &body)
+    }
+
+    fn startup_message(params: &[(&str, &str)]) -> Vec<u8> {
+        let mut body = PROTOCOL_VERSION_3.to_be_bytes().to_vec();
+        for (name, value) in params {
+            push_cstr(&mut body, name);
+            push_cstr(&mut body, value);
+        }
+        body.push(0);
+        with_length(None, &body)
+    }
+
+    /// The null terminated fields of a startup message body.
+    fn startup_params(body: &[u8]) -> Vec<String> {
+        body[4..]
+            .split(|b| *b == 0)
+            .map(|field| String::from_utf8_lossy(field).into_owned())
+            .filter(|field| !field.is_empty())
+            .collect()
+    }
+
+    #[test]
+    fn handshake_logs_the_client_in_as_the_configured_user() {
+        let (mut client, mut proxy_client) = pair();
+        let (mut proxy_server, mut server) = pair();
+
+        let server = thread::spawn(move || {
+            let len = read_u32(&mut server).unwrap() as usize;
+            let mut body = vec![0; len - 4];
+            server.read_exact(&mut body).unwrap();
+
+            server
+                .write_all(&auth_request(AUTH_CLEARTEXT, &[]))
+                .unwrap();
+            let (tag, password) = read_message(&mut server).unwrap();
+            assert_eq!(tag, b'p');
+            assert_eq!(password, b"secret\0");
+            server.write_all(&auth_request(AUTH_OK, &[])).unwrap();
+
+            startup_params(&body)
+        });
+
+        let mut ssl_request = 8u32.to_be_bytes().to_vec();
+        ssl_request.extend_from_slice(&SSL_REQUEST.to_be_bytes());
+        client.write_all(&ssl_request).unwrap();
+        client
+            .write_all(&startup_message(&[
+                ("user", "someone"),
+                ("options", "-c search_path=x"),
+            ]))
+            .unwrap();
+
+        let upstream = Upstream {
+            credentials: Arc::new(Mutex::new(credentials(Some("secret")))),
+            database: "appdb".to_owned(),
+            options: Some("-c default_transaction_read_only=on".to_owned()),
+        };
+        handshake(&upstream, &mut proxy_client, &mut proxy_server).unwrap();
+
+        let mut ssl_answer = [0; 1];
+        client.read_exact(&mut ssl_answer).unwrap();
+        assert_eq!(&ssl_answer, b"N");
+        assert_eq!(
+            read_message(&mut client).unwrap(),
+            (b'R', AUTH_OK.to_be_bytes().to_vec())
+        );
+        assert_eq!(
+            server.join().unwrap(),
+            vec![
+                "user",
+                "app",
+                "options",
+                "-c search_path=x -c default_transaction_read_only=on",
+                "database",
+                "appdb",
+            ]
+        );
+    }
+
+    #[test]
+    fn handshake_passes_cancel_requests_on() {
+        let (mut client, mut proxy_client) = pair();
+        let (mut proxy_server, mut server) = pair();
+
+        let mut cancel = CANCEL_REQUEST.to_be_bytes().to_vec();
+        cancel.extend_from_slice(&[0, 0, 0, 7, 0, 0, 0, 9]);
+        client.write_all(&with_length(None, &cancel)).unwrap();
+
+        let upstream = Upstream {
+            credentials: Arc::new(Mutex::new(credentials(None))),
+            database: "appdb".to_owned(),
+            options: None,
+        };
+        handshake(&upstream, &mut proxy_client, &mut proxy_server).unwrap();
+
+        let mut forwarded = vec![0; 16];
+        server.read_exact(&mut forwarded).unwrap();
+        assert_eq!(forwarded, with_length(None, &cancel));
+    }
+
+    #[test]
+    fn handshake_reports_a_refused_login_to_the_client() {
+        let (mut client, mut proxy_client) = pair();
+        let (mut proxy_server, mut server) = pair();
+
+        thread::spawn(move || {
+            let len = read_u32(&mut server).unwrap() as usize;
+            let mut body = vec![0; len - 4];
+            server.read_exact(&mut body).unwrap();
+            let mut error = b"SFATAL\0".to_vec();
+            push_cstr(&mut error, "Mpassword authentication failed");
+            error.push(0);
+            server.write_all(&with_length(Some(b'E'), &error)).unwrap();
+        });
+
+        client
+            .write_all(&startup_message(&[("user", "x")]))
+            .unwrap();
+        let upstream = Upstream {
+            credentials: Arc::new(Mutex::new(credentials(Some("wrong")))),
+            database: "appdb".to_owned(),
+            options: None,
+        };
+        let error = handshake(&upstream, &mut proxy_client, &mut proxy_server).unwrap_err();
+        assert!(error.to_string().contains("password authentication failed"));
+
+        let (tag, body) = read_message(&mut client).unwrap();
+        assert_eq!(tag, b'E');
+        assert!(error_message(&body).starts_with("figcli: server refused the login"));
+    }
+
+    #[test]
+    fn authenticate_answers_md5_with_the_salted_hash() {
+        let (mut proxy, mut server) = pair();
+
+        let stub = thread::spawn(move || {
+            server
+                .write_all(&auth_request(AUTH_MD5, &[1, 2, 3, 4]))
+                .unwrap();
+            let (_, answer) = read_message(&mut server).unwrap();
+            server.write_all(&auth_request(AUTH_OK, &[])).unwrap();
+            answer
+        });
+
+        authenticate(&mut proxy, &credentials(Some("secret")), true).unwrap();
+
+        let inner = format!("{:x}", md5::compute("secretapp"));
+        let mut outer = inner.into_bytes();
+        outer.extend_from_slice(&[1, 2, 3, 4]);
+        let expected = format!("md5{:x}\0", md5::compute(outer));
+        assert_eq!(stub.join().unwrap(), expected.into_bytes());
+    }
+
+    #[test]
+    fn authenticate_completes_a_scram_exchange() {
+        let (mut proxy, mut server) = pair();
+        let salt = b"0123456789abcdef";
+
+        let stub = thread::spawn(move || {
+            let mut mechanisms = Vec::new();
+            push_cstr(&mut mechanisms, SCRAM_SHA_256);
+            mechanisms.push(0);
+            server
+                .write_all(&auth_request(AUTH_SASL, &mechanisms))
+                .unwrap();
+
+            let (_, initial) = read_message(&mut server).unwrap();
+            let first = String::from_utf8_lossy(&initial[SCRAM_SHA_256.len() + 5..]).into_owned();
+            let first_bare = first.strip_prefix("n,,").unwrap().to_owned();
+            let nonce = first_bare.rsplit_once("r=").unwrap().1.to_owned();
+            let server_first = format!("r={}server,s={},i=4096", nonce, base64::encode(salt));
+            server
+                .write_all(&auth_request(AUTH_SASL_CONTINUE, server_first.as_bytes()))
+                .unwrap();
+
+            let (_, last) = read_message(&mut server).unwrap();
+            let last = String::from_utf8(last).unwrap();
+            let (without_proof, proof) = last.rsplit_once(",p=").unwrap();
+            let auth_message = format!("{},{},{}", first_bare, server_first, without_proof);
+
+            let server_final = scram::server_final(
+                "secret",
+                salt,
+                &auth_message,
+                &base64::decode(proof).unwrap(),
+            )
+            .expect("valid client proof");
+            server
+                .write_all(&auth_request(AUTH_SASL_FINAL, server_final.as_bytes()))
+                .unwrap();
+            server.write_all(&auth_request(AUTH_OK, &[])).unwrap();
+        });
+
+        authenticate(&mut proxy, &credentials(Some("secret")), true).unwrap();
+        stub.join().unwrap();
+    }
+
+    #[test]
+    fn authenticate_needs_a_password_when_asked_for_one() {
+        let (mut proxy, mut server) = pair();
+        server
+            .write_all(&auth_request(AUTH_CLEARTEXT, &[]))
+            .unwrap();
+
+        let error = authenticate(&mut proxy, &credentials(None), true).unwrap_err();
+        assert!(error.to_string().contains("none is configured"));
+    }
+
+    #[test]
+    fn authenticate_rejects_unsupported_methods() {
+        let (mut proxy, mut server) = pair();
+        // GSSAPI
+        server.write_all(&auth_request(7, &[])).unwrap();
+
+        let error = authenticate(&mut proxy, &credentials(Some("secret")), true).unwrap_err();
+        assert!(error
+            .to_string()
+            .contains("unsupported authentication method 7"));
+    }
+
+    #[test]
+    fn authenticate_keeps_plain_passwords_off_unencrypted_connections() {
+        for (method, data) in [(AUTH_CLEARTEXT, vec![]), (AUTH_MD5, vec![1, 2, 3, 4])] {
+            let (mut proxy, mut server) = pair();
+            server.write_all(&auth_request(method, &data)).unwrap();
+
+            let error = authenticate(&mut proxy, &credentials(Some("secret")), false).unwrap_err();
+            assert!(error.to_string().contains("over an unencrypted connection"));
+
+            // nothing was sent to the server
+            drop(proxy);
+            let mut sent = vec![];
+            server.read_to_end(&mut sent).unwrap();
+            assert!(sent.is_empty());
+        }
+    }
+}
@@ -0,0 +1,249 @@
+use std::io;
+use std::net::{Shutdown, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+const RETRY_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Runs on every new connection once the upstream is connected, before any
+/// traffic is piped, e.g. to speak a protocol's startup on the client's
+/// behalf. Gets the client and the upstream connection, in that order.
+pub type Handshake = dyn Fn(&mut TcpStream, &mut TcpStream) -> io::Result<()> + Send + Sync;
+
+/// Accepts connections on `listen_addr:port` and pipes each one to
+/// `upstream` (`host:port`) on its own threads, until figcli exits.
+///
+/// The upstream is connected to per client, so while the tunnel behind it is
+/// reconnecting new clients wait (up to `upstream_timeout`) instead of
+/// being refused, and the local port stays the same throughout.
+pub fn spawn(
+    listen_addr: &str,
+    port: u16,
+    upstream: String,
+    upstream_timeout: Duration,
+) -> io::Result<JoinHandle<()>> {
+    spawn_with_handshake(
+        listen_addr,
+        port,
+        upstream,
+        upstream_timeout,
+        Arc::new(|_: &mut TcpStream, _: &mut TcpStream| Ok(())),
+    )
+}
+
+/// Like `spawn`, running `handshake` on every connection first.
+pub fn spawn_with_handshake(
+    listen_addr: &str,
+    port: u16,
+    upstream: String,
+    upstream_timeout: Duration,
+    handshake: Arc<Handshake>,
+) -> io::Result<JoinHandle<()>> {
+    let listener = TcpListener::bind((listen_addr, port))?;
+    let ids = Arc::new(AtomicUsize::new(0));
+
+    Ok(thread::spawn(move || {
+        for client in listener.incoming() {
+            let client = match client {
+                Ok(client) => client,
+                Err(e) => {
+                    eprintln!("relay: could not accept connection: {}", e);
+                    continue;
+                }
+            };
+            let id = ids.fetch_add(1, Ordering::SeqCst) + 1;
+            let upstream = upstream.clone();
+            let handshake = handshake.clone();
+
+            thread::spawn(move || relay(id, client, &upstream, upstream_timeout, &*handshake));
+        }
+    }))
+}
+
+fn relay(
+    id: usize,
+    mut client: TcpStream,
+    upstream: &str,
+    timeout: Duration,
+    handshake: &Handshake,
+) {
+    let peer = client
+        .peer_addr()
+        .map(|addr| addr.to_string())
+        .unwrap_or_else(|_| "unknown".to_owned());
+    println!("relay: connection #{} from {} opened", id, peer);
+
+    let mut server = match connect(upstream, timeout) {
+        Ok(server) => server,
+        Err(e) => {
+            println!(
+                "relay: connection #{} from {} closed, upstream unavailable: {}",
+                id, peer, e
+            );
+            return;
+        }
+    };
+    if let Err(e) = handshake(&mut client, &mut server) {
+        println!(
+            "relay: connection #{} from {} closed, handshake failed: {}",
+            id, peer, e
+        );
+        return;
+    }
+
+    let started = Instant::now();
+    let (sent, received) = match pipe(client, server) {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            println!("relay: connection #{} from {} failed: {}", id, peer, e);
+            return;
+        }
+    };
+
+    println!(
+        "relay: connection #{} from {} closed after {}s ({} bytes sent, {} bytes received)",
+        id,
+        peer,
+        started.elapsed().as_secs(),
+        sent,
+        received
+    );
+}
+
+/// Connects to `addr`, retrying until `timeout` passes so a tunnel that is
+/// being restarted has time to come back.
+fn connect(addr: &str, timeout: Duration) -> io::Result<TcpStream> {
+    let deadline = Instant::now() + timeout;
+
+    loop {
+        let stream = addr.to_socket_addrs().and_then(|mut addrs| {
+            let addr = addrs.next().ok_or_else(|| {
+                io::Error::new(io::ErrorKind::NotFound, format!("{} not found", addr))
+            })?;
+            TcpStream::connect_timeout(&addr, RETRY_INTERVAL)
+        });
+
+        match stream {
+            Ok(stream) => return Ok(stream),
+            Err(e) if Instant::now() >= deadline => return Err(e),
+            Err(_) => thread::sleep(RETRY_INTERVAL),
+        }
+    }
+}
+
+/// Copies both directions until either side closes, returning the bytes sent
+/// to and received from the server.
+fn pipe(client: TcpStream, server: TcpStream) -> io::Result<(u64, u64)> {
+    let (mut client_read, mut client_write) = (client.try_clone()?, client);
+    let (mut server_read, mut server_write) = (server.try_clone()?, server);
+
+    let upload = thread::spawn(move || {
+        let sent = io::copy(&mut client_read, &mut server_write);
+        let _ = server_write.shutdown(Shutdown::Write);
+        sent
+    });
+    let received = io::copy(&mut server_read, &mut client_write);
+    let _ = client_write.shutdown(Shutdown::Write);
+
+    let sent = upload
+        .join()
+        .map_err(|_| io::Error::other("relay thread panicked"))?;
+
+    Ok((sent.unwrap_or(0), received.unwrap_or(0)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util;
+    use std::io::{Read, Write};
+
+    /// A relay on a free local port to `upstream_port`, and a client of it.
+    fn relay_to(upstream_port: u16, handshake: Option<Arc<Handshake>>) -> TcpStream {
+        let port = util::find_available_port().unwrap();
+        let upstream = format!("127.0.0.1:{}", upstream_port);
+        let timeout = Duration::from_secs(5);
+        match handshake {
+            Some(handshake) => {
+                spawn_with_handshake("127.0.0.1", port, upstream, timeout, handshake)
+            }
+            None => spawn("127.0.0.1", port, upstream, timeout),
+        }
+        .unwrap();
+
+        let client = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+        client
+    }
+
+    fn read_exactly(stream: &mut TcpStream, len: usize) -> Vec<u8> {
+        let mut buf = vec![0; len];
+        stream.read_exact(&mut buf).unwrap();
+        buf
+    }
+
+    #[test]
+    fn pipes_both_directions() {
+        let upstream = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = relay_to(upstream.local_addr().unwrap().port(), None);
+        let (mut server, _) = upstream.accept().unwrap();
+
+        client.write_all(b"ping").unwrap();
+        assert_eq!(read_exactly(&mut server, 4), b"ping");
+        server.write_all(b"pong").unwrap();
+        assert_eq!(read_exactly(&mut client, 4), b"pong");
+
+        client.shutdown(Shutdown::Write).unwrap();
+        let mut rest = vec![];
+        server.read_to_end(&mut rest).unwrap();
+        assert!(rest.is_empty());
+    }
+
+    #[test]
+    fn retries_upstream_until_it_is_back() {
+        let upstream_port = util::find_available_port().unwrap();
+        let mut client = relay_to(upstream_port, None);
+        client.write_all(b"early").unwrap();
+
+        thread::sleep(Duration::from_millis(600));
+        let upstream = TcpListener::bind(("127.0.0.1", upstream_port)).unwrap();
+        let (mut server, _) = upstream.accept().unwrap();
+
+        assert_eq!(read_exactly(&mut server, 5), b"early");
+    }
+
+    #[test]
+    fn closes_client_when_upstream_closes() {
+        let upstream = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = relay_to(upstream.local_addr().unwrap().port(), None);
+        let (mut server, _) = upstream.accept().unwrap();
+
+        server.write_all(b"bye").unwrap();
+        drop(server);
+
+        let mut received = vec![];
+        client.read_to_end(&mut received).unwrap();
+        assert_eq!(received, b"bye");
+    }
+
+    #[test]
+    fn runs_handshake_before_piping() {
+        let upstream = TcpListener::bind("127.0.0.1:0").unwrap();
+        let handshake: Arc<Handshake> =
+            Arc::new(|client: &mut TcpStream, server: &mut TcpStream| {
+                client.write_all(b"client-hello")?;
+                server.write_all(b"server-hello")
+            });
+        let mut client = relay_to(upstream.local_addr().unwrap().port(), Some(handshake));
+        let (mut server, _) = upstream.accept().unwrap();
+
+        assert_eq!(read_exactly(&mut client, 12), b"client-hello");
+        assert_eq!(read_exactly(&mut server, 12), b"server-hello");
+        server.write_all(b"data").unwrap();
+        assert_eq!(read_exactly(&mut client, 4), b"data");
+    }
+}
@@ -1,39 +1,809 @@
-use std::process::{Command, Stdio};
+use std::fmt;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::iter;
+use std::net::{Ipv4Addr, SocketAddr, TcpStream};
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
+use libc::c_int;
+
+use crate::signal;
 use crate::FigError;
 
-pub fn run_command(
-    command: &mut Command,
-    parent_command: Option<&mut Command>,
-    suppress_std: bool,
-) -> crate::Result<()> {
-    let parent = if let Some(cmd) = parent_command {
-        let proc = if suppress_std {
-            cmd.stderr(Stdio::null()).stdout(Stdio::null())
-        } else {
-            cmd
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+const PROBE_TIMEOUT: Duration = Duration::from_millis(250);
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+/// A parent that stayed up at least this long is considered healthy again, so
+/// the next failure starts over from `MIN_BACKOFF`.
+const HEALTHY_UPTIME: Duration = Duration::from_secs(60);
+/// A tunnel that failed this many times in a row without ever having been
+/// healthy is most likely misconfigured, supervision gives up on it.
+const MAX_UNHEALTHY_ATTEMPTS: u32 = 5;
+const STDERR_TAIL_BYTES: usize = 4096;
+const STDERR_TAIL_LINES: usize = 10;
+
+/// Describes when a parent process (e.g. `kubectl port-forward`) is usable.
+/// The parent is considered ready as soon as either the local `port` accepts a
+/// TCP connection or a line on its stdout/stderr contains one of `patterns`.
+#[derive(Clone, Debug)]
+pub struct Readiness {
+    pub port: Option<u16>,
+    pub patterns: Vec<&'static str>,
+    pub timeout: Duration,
+}
+
+impl Readiness {
+    pub fn new(port: Option<u16>, patterns: Vec<&'static str>, timeout: Duration) -> Self {
+        Readiness {
+            port,
+            patterns,
+            timeout,
+        }
+    }
+
+    fn port_open(&self) -> bool {
+        self.port.is_some_and(|port| {
+            let addr = SocketAddr::from((Ipv4Addr::LOCALHOST, port));
+            TcpStream::connect_timeout(&addr, PROBE_TIMEOUT).is_ok()
+        })
+    }
+}
+
+/// Why a command run by figcli failed, with enough detail for callers (and
+/// scripts wrapping figcli) to tell one failure from another.
+#[derive(Debug)]
+pub struct ExecFailure {
+    /// The full command line, e.g. `psql -h localhost -U user -p 5432 db`.
+    pub command: String,
+    pub reason: String,
+    pub status: Option<ExitStatus>,
+    /// A signal figcli received and forwarded to the command.
+    pub signal: Option<c_int>,
+    /// The last lines the command wrote to stderr.
+    pub stderr: Vec<String>,
+}
+
+impl ExecFailure {
+    /// The status figcli should exit with: the command's own exit code, or
+    /// 128 + the signal number if it was killed by (or figcli received) one,
+    /// and EX_UNAVAILABLE (69) if it never exited at all.
+    pub fn exit_code(&self) -> i32 {
+        if let Some(sig) = self.signal {
+            return 128 + sig;
+        }
+        match self.status {
+            Some(status) => status
+                .code()
+                .or_else(|| status.signal().map(|sig| 128 + sig))
+                .unwrap_or(69),
+            None => 69,
+        }
+    }
+}
+
+impl fmt::Display for ExecFailure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "`{}` {}", self.command, self.reason)?;
+        if let Some(status) = self.status {
+            write!(f, " ({})", status)?;
+        }
+        for line in self.stderr.iter() {
+            write!(f, "\n  | {}", line)?;
+        }
+        Ok(())
+    }
+}
+
+/// The last `STDERR_TAIL_BYTES` a process wrote to stderr.
+#[derive(Clone, Default)]
+struct Tail(Arc<Mutex<Vec<u8>>>);
+
+impl Tail {
+    fn push(&self, bytes: &[u8]) {
+        if let Ok(mut buf) = self.0.lock() {
+            buf.extend_from_slice(bytes);
+            let excess = buf.len().saturating_sub(STDERR_TAIL_BYTES);
+            buf.drain(..excess);
+        }
+    }
+
+    fn lines(&self) -> Vec<String> {
+        let buf = match self.0.lock() {
+            Ok(buf) => buf,
+            Err(_) => return vec![],
         };
-        let proc = proc.spawn()?;
+        let text = String::from_utf8_lossy(&buf);
+        let lines = text
+            .lines()
+            .filter(|l| !l.trim().is_empty())
+            .map(ToOwned::to_owned)
+            .collect::<Vec<_>>();
+
+        lines[lines.len().saturating_sub(STDERR_TAIL_LINES)..].to_vec()
+    }
+}
+
+/// Copies everything read from `source` to `sink` as it arrives (unless
+/// `echo` is off), records it in `tail`, and notifies `ready` the first time
+/// a line matches one of `patterns`. The reader keeps draining after
+/// readiness so the process never blocks on a full pipe.
+fn watch_output<R, W>(
+    mut source: R,
+    mut sink: W,
+    echo: bool,
+    tail: Option<Tail>,
+    ready: Option<(Vec<&'static str>, Sender<()>)>,
+) where
+    R: Read + Send + 'static,
+    W: Write + Send + 'static,
+{
+    thread::spawn(move || {
+        let mut chunk = [0u8; 4096];
+        let mut line = Vec::new();
+        let mut ready = ready;
+
+        loop {
+            let n = match source.read(&mut chunk) {
+                Ok(0) | Err(_) => break,
+                Ok(n) => n,
+            };
+            if echo {
+                let _ = sink.write_all(&chunk[..n]);
+                let _ = sink.flush();
+            }
+            if let Some(tail) = tail.as_ref() {
+                tail.push(&chunk[..n]);
+            }
+            if let Some((patterns, sender)) = ready.as_ref() {
+                line.extend_from_slice(&chunk[..n]);
+                let text = String::from_utf8_lossy(&line);
+                if patterns.iter().any(|p| text.contains(p)) {
+                    let _ = sender.send(());
+                    ready = None;
+                }
+                // only the current, unfinished line has to be kept around
+                if let Some(pos) = line.iter().rposition(|&b| b == b'\n') {
+                    line.drain(..=pos);
+                }
+            }
+        }
+    });
+}
+
+/// How long a process gets to exit after SIGTERM before it is killed. Long
+/// enough for cleanup on the way out, like the port-forward script deleting
+/// its relay pod, which is bounded by its own request timeout.
+const TERMINATE_GRACE: Duration = Duration::from_secs(10);
+
+/// A spawned process that is torn down when dropped, so it can't outlive
+/// figcli on any exit path. Processes placed in their own process group are
+/// signalled as a group, which also reaches anything they spawned themselves
+/// (e.g. the `kubectl` processes of the port-forward script).
+struct Managed {
+    name: String,
+    command_line: String,
+    proc: Child,
+    own_group: bool,
+    echo: bool,
+    stderr: Tail,
+}
+
+impl Managed {
+    /// Spawns `command` with its stderr captured (and echoed unless `echo` is
+    /// off). When `ready` is given its stdout is watched as well, see
+    /// `watch_output`; otherwise stdout is left to the caller. Interactive
+    /// children, the ones sharing the terminal's process group, keep the
+    /// terminal as their stderr: piping it would make tools like `psql` or
+    /// `$EDITOR` treat stderr as non-interactive.
+    fn spawn(
+        command: &mut Command,
+        own_group: bool,
+        echo: bool,
+        ready: Option<(Vec<&'static str>, Sender<()>)>,
+    ) -> crate::Result<Self> {
+        if own_group {
+            command.process_group(0);
+        }
+        if ready.is_some() {
+            command.stdout(Stdio::piped());
+        }
+        if own_group || ready.is_some() {
+            command.stderr(Stdio::piped());
+        }
+        let mut proc = command.spawn()?;
+        let stderr = Tail::default();
+
+        if ready.is_some() {
+            if let Some(stdout) = proc.stdout.take() {
+                watch_output(stdout, io::stdout(), echo, None, ready.clone());
+            }
+        }
+        if let Some(source) = proc.stderr.take() {
+            watch_output(source, io::stderr(), echo, Some(stderr.clone()), ready);
+        }
+
+        Ok(Managed {
+            name: command.get_program().to_string_lossy().into_owned(),
+            command_line: command_line(command),
+            proc,
+            own_group,
+            echo,
+            stderr,
+        })
+    }
+
+    /// Describes why the process failed; its stderr tail is only included if
+    /// it wasn't already shown to the user.
+    fn failure(&self, reason: String, status: Option<ExitStatus>, sig: Option<c_int>) -> FigError {
+        FigError::ExecError(ExecFailure {
+            command: self.command_line.clone(),
+            reason,
+            status,
+            signal: sig,
+            stderr: if self.echo {
+                vec![]
+            } else {
+                self.stderr.lines()
+            },
+        })
+    }
+
+    fn try_wait(&mut self) -> crate::Result<Option<ExitStatus>> {
+        self.proc.try_wait().map_err(From::from)
+    }
+
+    fn forward(&self, sig: c_int) -> crate::Result<()> {
+        signal::send(self.proc.id(), sig, self.own_group).map_err(From::from)
+    }
+
+    fn terminate(&mut self) -> crate::Result<()> {
+        if self.try_wait()?.is_some() {
+            return Ok(());
+        }
+
+        self.forward(libc::SIGTERM)?;
+        let started = Instant::now();
+        while started.elapsed() < TERMINATE_GRACE {
+            if self.try_wait()?.is_some() {
+                return Ok(());
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+
+        self.forward(libc::SIGKILL)?;
+        self.proc.wait()?;
+
+        Ok(())
+    }
+}
+
+impl Drop for Managed {
+    fn drop(&mut self) {
+        let _ = self.terminate();
+    }
+}
+
+/// Whether `proc`, spawned at `started`, has become ready: `false` while it
+/// is still starting, an error once it exited or ran out of time.
+fn check_ready(
+    proc: &mut Managed,
+    readiness: &Readiness,
+    ready: &Receiver<()>,
+    started: Instant,
+) -> crate::Result<bool> {
+    if let Some(status) = proc.try_wait()? {
+        return Err(proc.failure(
+            "exited before becoming ready".to_owned(),
+            Some(status),
+            None,
+        ));
+    }
+
+    if ready.try_recv().is_ok() || readiness.port_open() {
+        return Ok(true);
+    }
+
+    if started.elapsed() >= readiness.timeout {
+        return Err(proc.failure(
+            format!(
+                "was not ready after {} seconds",
+                readiness.timeout.as_secs()
+            ),
+            None,
+            None,
+        ));
+    }
+
+    Ok(false)
+}
+
+fn wait_until_ready(
+    proc: &mut Managed,
+    readiness: &Readiness,
+    ready: Receiver<()>,
+) -> crate::Result<()> {
+    let started = Instant::now();
+
+    loop {
+        if let Some(sig) = signal::take() {
+            return Err(proc.failure(
+                format!("interrupted by {} before becoming ready", signal::name(sig)),
+                None,
+                Some(sig),
+            ));
+        }
+
+        if check_ready(proc, readiness, &ready, started)? {
+            return Ok(());
+        }
+
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+/// Spawns a parent whose output is watched for `readiness`, which the
+/// returned receiver is notified of. Parents are always placed in their own
+/// process group: a Ctrl-C in the terminal is meant for the child (e.g.
+/// cancelling a psql query), not for the tunnel underneath it.
+fn start_parent(
+    cmd: &mut Command,
+    readiness: &Readiness,
+    suppress_std: bool,
+) -> crate::Result<(Managed, Receiver<()>)> {
+    let (sender, receiver) = mpsc::channel();
+    let ready = Some((readiness.patterns.clone(), sender));
+    let proc = Managed::spawn(cmd, true, !suppress_std, ready)?;
+
+    println!("Waiting for {} to become ready", proc.name);
+
+    Ok((proc, receiver))
+}
+
+fn spawn_parent(
+    cmd: &mut Command,
+    readiness: &Readiness,
+    suppress_std: bool,
+) -> crate::Result<Managed> {
+    let (mut proc, ready) = start_parent(cmd, readiness, suppress_std)?;
+    wait_until_ready(&mut proc, readiness, ready)?;
+
+    Ok(proc)
+}
+
+/// Exponential backoff between restarts of a dropped parent process.
+struct Backoff {
+    attempt: u32,
+}
+
+impl Backoff {
+    fn new() -> Self {
+        Backoff { attempt: 0 }
+    }
+
+    fn reset(&mut self) {
+        self.attempt = 0;
+    }
+
+    fn next_delay(&mut self) -> Duration {
+        let delay = MIN_BACKOFF
+            .checked_mul(1 << self.attempt.min(16))
+            .map_or(MAX_BACKOFF, |d| d.min(MAX_BACKOFF));
+        self.attempt += 1;
+        delay
+    }
+}
+
+/// When to restart a supervised tunnel after it failed: with backoff, and
+/// not at all once it failed `MAX_UNHEALTHY_ATTEMPTS` times without ever
+/// having been healthy.
+struct Restarts {
+    backoff: Backoff,
+    failures: u32,
+    was_healthy: bool,
+}
+
+impl Restarts {
+    fn new() -> Self {
+        Restarts {
+            backoff: Backoff::new(),
+            failures: 0,
+            was_healthy: false,
+        }
+    }
+
+    /// Records a failure after `uptime` and returns the delay before the
+    /// next attempt, or `None` to give up.
+    fn failed(&mut self, uptime: Duration) -> Option<Duration> {
+        if uptime >= HEALTHY_UPTIME {
+            self.was_healthy = true;
+            self.backoff.reset();
+        }
+        self.failures += 1;
+        if !self.was_healthy && self.failures >= MAX_UNHEALTHY_ATTEMPTS {
+            return None;
+        }
+
+        Some(self.backoff.next_delay())
+    }
+}
+
+fn giving_up() -> String {
+    format!(
+        "failed {} times without ever staying up, giving up",
+        MAX_UNHEALTHY_ATTEMPTS
+    )
+}
+
+/// A parent process that is restarted with backoff whenever it exits while
+/// the child that depends on it is still running. Its command is built anew
+/// for every restart. Restarted parents become ready in the background, so
+/// signals for the child are handled in the meantime.
+struct Supervised<'a> {
+    command: &'a mut dyn FnMut() -> crate::Result<Command>,
+    name: String,
+    readiness: &'a Readiness,
+    suppress_std: bool,
+    proc: Option<Managed>,
+    /// Set while a restarted `proc` is not ready yet.
+    starting: Option<Receiver<()>>,
+    started: Instant,
+    restarts: Restarts,
+    restart_at: Option<Instant>,
+    reconnects: u32,
+}
+
+impl<'a> Supervised<'a> {
+    fn start(
+        command: &'a mut dyn FnMut() -> crate::Result<Command>,
+        readiness: &'a Readiness,
+        suppress_std: bool,
+    ) -> crate::Result<Self> {
+        let mut cmd = command()?;
+        let proc = spawn_parent(&mut cmd, readiness, suppress_std)?;
+
+        Ok(Supervised {
+            command,
+            name: proc.name.clone(),
+            readiness,
+            suppress_std,
+            proc: Some(proc),
+            starting: None,
+            started: Instant::now(),
+            restarts: Restarts::new(),
+            restart_at: None,
+            reconnects: 0,
+        })
+    }
 
-        // TODO instead of static delay read stdout for matching regex?
+    /// Schedules the next restart after a failure `uptime` into the run, or
+    /// returns `false` if the parent should be given up on.
+    fn schedule_restart(&mut self, uptime: Duration) -> bool {
+        match self.restarts.failed(uptime) {
+            Some(delay) => {
+                println!("Reconnecting {} in {} seconds", self.name, delay.as_secs());
+                self.restart_at = Some(Instant::now() + delay);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Checks on the parent, restarting it once its backoff delay has passed.
+    /// Fails once the parent is given up on.
+    fn poll(&mut self) -> crate::Result<()> {
+        if let Some(proc) = self.proc.as_mut() {
+            if let Some(ready) = self.starting.as_ref() {
+                match check_ready(proc, self.readiness, ready, self.started) {
+                    Ok(false) => {}
+                    Ok(true) => {
+                        self.starting = None;
+                        self.reconnects += 1;
+                        println!("Reconnected {} (reconnect #{})", self.name, self.reconnects);
+                    }
+                    Err(e) => {
+                        println!("Failed to reconnect {}: {}", self.name, e);
+                        let status = proc.try_wait()?;
+                        let error = proc.failure(giving_up(), status, None);
+                        self.proc = None;
+                        self.starting = None;
+                        if !self.schedule_restart(Duration::ZERO) {
+                            return Err(error);
+                        }
+                    }
+                }
+            } else if let Some(status) = proc.try_wait()? {
+                println!("{} exited ({}) while still in use", self.name, status);
+                let error = proc.failure(giving_up(), Some(status), None);
+                self.proc = None;
+                if !self.schedule_restart(self.started.elapsed()) {
+                    return Err(error);
+                }
+            }
+            return Ok(());
+        }
+
+        match self.restart_at {
+            Some(at) if Instant::now() >= at => {
+                self.restart_at = None;
+                let (readiness, suppress_std) = (self.readiness, self.suppress_std);
+                match (self.command)()
+                    .and_then(|mut cmd| start_parent(&mut cmd, readiness, suppress_std))
+                {
+                    Ok((proc, ready)) => {
+                        self.proc = Some(proc);
+                        self.starting = Some(ready);
+                        self.started = Instant::now();
+                    }
+                    Err(e) => {
+                        println!("Failed to reconnect {}: {}", self.name, e);
+                        if !self.schedule_restart(Duration::ZERO) {
+                            return Err(e);
+                        }
+                    }
+                }
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Reacts to a signal received while `proc` is running. The first one is
+/// forwarded so the process can shut down on its own terms, a second one
+/// kills it. A SIGINT is not forwarded to a process sharing our foreground
+/// process group, since the terminal already delivered it there.
+fn handle_signal(proc: &Managed, sig: c_int, interrupted: &mut Option<c_int>) -> crate::Result<()> {
+    if sig == libc::SIGINT && !proc.own_group {
+        return Ok(());
+    }
+
+    if interrupted.is_some() {
         println!(
-            "Sleeping 5 seconds to give parent process time to startup. This needs to be fixed!"
+            "Received {} again, killing {}",
+            signal::name(sig),
+            proc.name
         );
-        thread::sleep(Duration::from_millis(5_000));
+        proc.forward(libc::SIGKILL)
+    } else {
+        *interrupted = Some(sig);
+        proc.forward(sig)
+    }
+}
+
+fn interrupted_error(proc: &Managed, status: Option<ExitStatus>, sig: c_int) -> FigError {
+    proc.failure(
+        format!("interrupted by {}", signal::name(sig)),
+        status,
+        Some(sig),
+    )
+}
+
+/// Renders `command` the way it would be typed into a shell.
+fn command_line(command: &Command) -> String {
+    iter::once(command.get_program())
+        .chain(command.get_args())
+        .map(|arg| {
+            let arg = arg.to_string_lossy();
+            if arg.is_empty() || arg.contains(char::is_whitespace) {
+                format!("'{}'", arg)
+            } else {
+                arg.into_owned()
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Runs all `commands` at once, merging their stdout into ours line by line,
+/// each line prefixed with the command's label. Lines rejected by `filter`
+/// are dropped. Returns once every command has exited.
+pub fn run_merged<F>(commands: Vec<(String, Command)>, filter: F) -> crate::Result<()>
+where
+    F: Fn(&str) -> bool + Send + Sync + 'static,
+{
+    let _handlers = signal::Handlers::install()?;
+    let width = commands
+        .iter()
+        .map(|(label, _)| label.len())
+        .max()
+        .unwrap_or(0);
+    let filter = Arc::new(filter);
+    let stdout = Arc::new(Mutex::new(io::stdout()));
+    let mut procs = Vec::with_capacity(commands.len());
+    let mut readers = Vec::with_capacity(commands.len());
+
+    for (label, mut command) in commands {
+        let mut proc = Managed::spawn(command.stdout(Stdio::piped()), true, true, None)?;
+
+        if let Some(source) = proc.proc.stdout.take() {
+            let filter = Arc::clone(&filter);
+            let stdout = Arc::clone(&stdout);
+            readers.push(thread::spawn(move || {
+                for line in BufReader::new(source).lines() {
+                    let line = match line {
+                        Ok(line) => line,
+                        Err(_) => break,
+                    };
+                    if filter(&line) {
+                        if let Ok(mut out) = stdout.lock() {
+                            let _ = writeln!(out, "{:width$} | {}", label, line, width = width);
+                        }
+                    }
+                }
+            }));
+        }
+        procs.push(proc);
+    }
+
+    let mut interrupted = None;
+    let mut failed = None;
+    let mut running = procs.len();
+
+    while running > 0 {
+        running = 0;
+        for (i, proc) in procs.iter_mut().enumerate() {
+            match proc.try_wait()? {
+                None => running += 1,
+                Some(status) if !status.success() && failed.is_none() => {
+                    failed = Some((i, status));
+                }
+                Some(_) => {}
+            }
+        }
+        if let Some(sig) = signal::take() {
+            for proc in procs.iter() {
+                let mut first = interrupted;
+                handle_signal(proc, sig, &mut first)?;
+            }
+            interrupted = Some(sig);
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+
+    // let the readers flush whatever is left in the pipes
+    for reader in readers {
+        let _ = reader.join();
+    }
+
+    match (interrupted, failed) {
+        (Some(sig), _) => Err(FigError::ExecError(ExecFailure {
+            command: procs
+                .iter()
+                .map(|p| p.command_line.as_str())
+                .collect::<Vec<_>>()
+                .join(" & "),
+            reason: format!("interrupted by {}", signal::name(sig)),
+            status: None,
+            signal: Some(sig),
+            stderr: vec![],
+        })),
+        (None, Some((i, status))) => {
+            Err(procs[i].failure("exited unsuccessfully".to_owned(), Some(status), None))
+        }
+        (None, None) => Ok(()),
+    }
+}
 
-        Some(proc)
+/// Runs `command` to completion without a terminal and returns its stdout.
+pub fn capture_command(command: &mut Command) -> crate::Result<String> {
+    let output = command.stdin(Stdio::null()).output()?;
+
+    if output.status.success() {
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
     } else {
-        None
+        let stderr = Tail::default();
+        stderr.push(&output.stderr);
+
+        Err(FigError::ExecError(ExecFailure {
+            command: command_line(command),
+            reason: "exited unsuccessfully".to_owned(),
+            status: Some(output.status),
+            signal: None,
+            stderr: stderr.lines(),
+        }))
+    }
+}
+
+/// Runs the tunnel built by `command` on its own, restarting it with
+/// backoff every time it exits unsuccessfully. The command is built again
+/// for every restart, so whatever it depends on (e.g. the pod it forwards
+/// to) is looked up afresh. A successful exit ends supervision, and so does
+/// failing `MAX_UNHEALTHY_ATTEMPTS` times before it was ever healthy.
+pub fn run_supervised<F>(mut command: F) -> crate::Result<()>
+where
+    F: FnMut() -> crate::Result<Command>,
+{
+    let _handlers = signal::Handlers::install()?;
+    let mut restarts = Restarts::new();
+    let mut reconnects = 0;
+    let mut interrupted = None;
+    let mut cmd = command()?;
+
+    loop {
+        let started = Instant::now();
+        let mut proc = Managed::spawn(&mut cmd, true, true, None)?;
+
+        let status = loop {
+            if let Some(status) = proc.try_wait()? {
+                break status;
+            }
+            if let Some(sig) = signal::take() {
+                handle_signal(&proc, sig, &mut interrupted)?;
+            }
+            thread::sleep(POLL_INTERVAL);
+        };
+
+        if let Some(sig) = interrupted {
+            return Err(interrupted_error(&proc, Some(status), sig));
+        }
+        if status.success() {
+            return Ok(());
+        }
+
+        let mut uptime = started.elapsed();
+        cmd = loop {
+            let delay = match restarts.failed(uptime) {
+                Some(delay) => delay,
+                None => return Err(proc.failure(giving_up(), Some(status), None)),
+            };
+            uptime = Duration::ZERO;
+            reconnects += 1;
+            println!(
+                "{} exited ({}); reconnecting in {} seconds (reconnect #{})",
+                proc.name,
+                status,
+                delay.as_secs(),
+                reconnects
+            );
+
+            let restart_at = Instant::now() + delay;
+            while Instant::now() < restart_at {
+                if let Some(sig) = signal::take() {
+                    return Err(interrupted_error(&proc, Some(status), sig));
+                }
+                thread::sleep(POLL_INTERVAL);
+            }
+
+            match command() {
+                Ok(cmd) => break cmd,
+                Err(e) => println!("Failed to reconnect {}: {}", proc.name, e),
+            }
+        };
+    }
+}
+
+/// Runs `command` to completion, optionally on top of a supervised parent
+/// process it depends on (built, and rebuilt for restarts, by the function
+/// passed with its readiness). SIGINT, SIGTERM and SIGHUP are intercepted for the
+/// duration and forwarded to `command`; the parent is torn down on every exit
+/// path, including errors.
+pub fn run_command(
+    command: &mut Command,
+    parent_command: Option<(&mut dyn FnMut() -> crate::Result<Command>, &Readiness)>,
+    suppress_std: bool,
+) -> crate::Result<()> {
+    let _handlers = signal::Handlers::install()?;
+
+    let mut parent = match parent_command {
+        Some((cmd, readiness)) => Some(Supervised::start(cmd, readiness, suppress_std)?),
+        None => None,
     };
 
     let command_proc = if suppress_std {
-        command.stderr(Stdio::null()).stdout(Stdio::null())
+        command.stdout(Stdio::null())
     } else {
         command
     };
-    let mut command_proc = command_proc.spawn()?;
+    // An interactive child has to stay in the terminal's foreground process
+    // group, anything else is managed as a group of its own.
+    let own_group = suppress_std || !signal::stdin_is_tty();
+    let mut command_proc = Managed::spawn(command_proc, own_group, !suppress_std, None)?;
+    let mut interrupted = None;
 
     loop {
         let child_result = command_proc.try_wait()?;
@@ -41,23 +811,164 @@ pub fn run_command(
         match child_result {
             Some(status) => {
                 // cleanup parent
-                if let Some(mut parent_proc) = parent {
-                    let parent_result = parent_proc.try_wait()?;
-
-                    if parent_result.is_none() {
-                        parent_proc.kill()?;
-                    }
-                }
+                drop(parent);
 
-                return if status.success() {
+                return if let Some(sig) = interrupted {
+                    Err(interrupted_error(&command_proc, Some(status), sig))
+                } else if status.success() {
                     Ok(())
                 } else {
-                    Err(FigError::ExecError(
-                        "child exited unsuccessfully".to_owned(),
+                    Err(command_proc.failure(
+                        "exited unsuccessfully".to_owned(),
+                        Some(status),
+                        None,
                     ))
                 };
             }
-            _ => thread::sleep(Duration::from_millis(200)),
+            _ => {
+                if let Some(sig) = signal::take() {
+                    handle_signal(&command_proc, sig, &mut interrupted)?;
+                }
+                if let (Some(parent), None) = (parent.as_mut(), interrupted) {
+                    parent.poll()?;
+                }
+                thread::sleep(POLL_INTERVAL)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    fn sh(script: &str) -> Command {
+        let mut cmd = Command::new("sh");
+        cmd.args(["-c", script]);
+        cmd
+    }
+
+    fn failure(result: crate::Result<Managed>) -> ExecFailure {
+        match result {
+            Err(FigError::ExecError(failure)) => failure,
+            Err(e) => panic!("unexpected error {}", e),
+            Ok(_) => panic!("became ready"),
+        }
+    }
+
+    #[test]
+    fn ready_when_port_accepts_connections() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let readiness = Readiness::new(Some(port), vec![], Duration::from_secs(5));
+
+        assert!(spawn_parent(&mut sh("sleep 10"), &readiness, true).is_ok());
+    }
+
+    #[test]
+    fn ready_on_pattern_split_across_writes() {
+        let readiness = Readiness::new(None, vec!["Forwarding from"], Duration::from_secs(5));
+        let script = "printf 'Forwar'; sleep 0.3; printf 'ding from 127.0.0.1\\n'; sleep 10";
+
+        assert!(spawn_parent(&mut sh(script), &readiness, true).is_ok());
+    }
+
+    #[test]
+    fn ready_on_pattern_in_stderr() {
+        let readiness = Readiness::new(None, vec!["listening"], Duration::from_secs(5));
+
+        assert!(spawn_parent(&mut sh("echo listening >&2; sleep 10"), &readiness, true).is_ok());
+    }
+
+    #[test]
+    fn not_ready_before_timeout() {
+        let readiness = Readiness::new(None, vec!["never"], Duration::from_secs(1));
+        let started = Instant::now();
+        let failure = failure(spawn_parent(&mut sh("sleep 10"), &readiness, true));
+
+        assert_eq!(failure.reason, "was not ready after 1 seconds");
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn exiting_before_ready_fails_with_status_and_stderr() {
+        let readiness = Readiness::new(None, vec!["never"], Duration::from_secs(5));
+        let failure = failure(spawn_parent(
+            &mut sh("echo no such pod >&2; sleep 0.3; exit 3"),
+            &readiness,
+            true,
+        ));
+
+        assert_eq!(failure.reason, "exited before becoming ready");
+        assert_eq!(failure.exit_code(), 3);
+        assert_eq!(failure.stderr, vec!["no such pod"]);
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_maximum() {
+        let mut backoff = Backoff::new();
+        let delays = (0..7).map(|_| backoff.next_delay().as_secs());
+
+        assert_eq!(delays.collect::<Vec<_>>(), vec![1, 2, 4, 8, 16, 30, 30]);
+        backoff.reset();
+        assert_eq!(backoff.next_delay(), MIN_BACKOFF);
+    }
+
+    #[test]
+    fn restarts_give_up_on_tunnels_that_never_stay_up() {
+        let mut restarts = Restarts::new();
+        for _ in 1..MAX_UNHEALTHY_ATTEMPTS {
+            assert!(restarts.failed(Duration::from_secs(1)).is_some());
+        }
+
+        assert_eq!(restarts.failed(Duration::from_secs(1)), None);
+    }
+
+    #[test]
+    fn restarts_keep_going_once_healthy() {
+        let mut restarts = Restarts::new();
+        for _ in 0..3 {
+            restarts.failed(Duration::ZERO);
+        }
+
+        assert_eq!(restarts.failed(HEALTHY_UPTIME), Some(MIN_BACKOFF));
+        for _ in 0..2 * MAX_UNHEALTHY_ATTEMPTS {
+            assert!(restarts.failed(Duration::ZERO).is_some());
+        }
+    }
+
+    #[test]
+    fn supervised_gives_up_on_parent_that_never_becomes_ready_again() {
+        let mut builds = 0;
+        let mut command = || {
+            builds += 1;
+            Ok(match builds {
+                1 => sh("echo up; sleep 0.3; exit 1"),
+                _ => sh("exit 1"),
+            })
+        };
+        let readiness = Readiness::new(None, vec!["up"], Duration::from_secs(5));
+        let mut supervised = Supervised::start(&mut command, &readiness, true).unwrap();
+
+        let started = Instant::now();
+        let result = loop {
+            assert!(started.elapsed() < Duration::from_secs(10));
+            // skip the backoff delays
+            if supervised.restart_at.is_some() {
+                supervised.restart_at = Some(Instant::now());
+            }
+            if let Err(e) = supervised.poll() {
+                break e;
+            }
+            thread::sleep(Duration::from_millis(50));
+        };
+        drop(supervised);
+
+        match result {
+            FigError::ExecError(failure) => assert_eq!(failure.reason, giving_up()),
+            e => panic!("unexpected error {}", e),
         }
+        assert_eq!(builds, MAX_UNHEALTHY_ATTEMPTS);
     }
 }
@@ -0,0 +1,714 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use regex::Regex;
+use toml::Value;
+use walkdir::{DirEntry, WalkDir};
+
+const MAX_DEPTH: usize = 6;
+const SKIPPED_DIRS: [&str; 7] = [
+    ".git",
+    ".gradle",
+    ".idea",
+    "build",
+    "node_modules",
+    "target",
+    "vendor",
+];
+const PLACEHOLDER_CONTEXT: &str = "<insert kubernetes context>";
+const PLACEHOLDER_PASSWORD: &str = "<insert password>";
+const PLACEHOLDER_USER: &str = "<insert user name>";
+const PLACEHOLDER_DATABASE: &str = "<insert database>";
+
+/// Postgres connection details pieced together from one or more files.
+#[derive(Clone, Debug, Default)]
+pub struct Database {
+    pub host: Option<String>,
+    pub port: Option<u16>,
+    pub user: Option<String>,
+    pub password: Option<String>,
+    pub database: Option<String>,
+    pub schema: Option<String>,
+}
+
+impl Database {
+    fn is_empty(&self) -> bool {
+        self.user.is_none() && self.password.is_none() && self.database.is_none()
+    }
+
+    fn is_local(&self) -> bool {
+        matches!(
+            self.host.as_deref(),
+            None | Some("localhost") | Some("127.0.0.1") | Some("0.0.0.0")
+        )
+    }
+
+    /// Fills in whatever is still unknown from `other`.
+    fn merge(&mut self, other: &Database) {
+        self.host = self.host.take().or_else(|| other.host.clone());
+        self.port = self.port.or(other.port);
+        self.user = self.user.take().or_else(|| other.user.clone());
+        self.password = self.password.take().or_else(|| other.password.clone());
+        self.database = self.database.take().or_else(|| other.database.clone());
+        self.schema = self.schema.take().or_else(|| other.schema.clone());
+    }
+}
+
+/// A Kubernetes deployment that runs a Postgres image.
+#[derive(Clone, Debug)]
+pub struct Deployment {
+    pub name: String,
+    pub namespace: Option<String>,
+}
+
+/// Everything `scan` could find out about the project's databases.
+#[derive(Debug, Default)]
+pub struct Findings {
+    /// Scanned files that contributed to the findings.
+    pub sources: Vec<PathBuf>,
+    pub local: Database,
+    pub remote: Database,
+    pub deployment: Option<Deployment>,
+    pub namespace: Option<String>,
+    pub cloudsql_instance: Option<String>,
+}
+
+impl Findings {
+    pub fn is_empty(&self) -> bool {
+        self.local.is_empty()
+            && self.remote.is_empty()
+            && self.deployment.is_none()
+            && self.namespace.is_none()
+            && self.cloudsql_instance.is_none()
+    }
+}
+
+struct Patterns {
+    jdbc_url: Regex,
+    postgres_url: Regex,
+    key_value: Regex,
+    compose_port: Regex,
+    cloudsql_instance: Regex,
+    metadata_name: Regex,
+    metadata_namespace: Regex,
+}
+
+impl Patterns {
+    fn new() -> Self {
+        // the patterns are constant, so failing to compile them is a bug
+        let re = |s: &str| Regex::new(s).expect("invalid scan pattern");
+
+        Patterns {
+            jdbc_url: re(r#"jdbc:postgresql://([^/:\s"']+)(?::(\d+))?/([\w\-]+)(?:\?([^\s"']*))?"#),
+            postgres_url: re(
+                r#"postgres(?:ql)?://(?:([^:@/\s"']+)(?::([^@/\s"']*))?@)?([^/:\s"']+)(?::(\d+))?/([\w\-]+)"#,
+            ),
+            key_value: re(r#"^\s*(?:-\s*)?([\w.\-]+)\s*[:=]\s*["']?([^"'#\s]+)["']?\s*(?:#.*)?$"#),
+            compose_port: re(r#"["']?(\d+):5432["']?"#),
+            cloudsql_instance: re(r"\b([a-z][a-z0-9\-]{4,}:[a-z]+-[a-z]+\d+:[a-z0-9\-]+)\b"),
+            metadata_name: re(r"(?m)^metadata:[ \t]*\n(?:[ ]{2}.*\n)*?[ ]{2}name:[ \t]*(\S+)"),
+            metadata_namespace: re(
+                r"(?m)^metadata:[ \t]*\n(?:[ ]{2}.*\n)*?[ ]{2}namespace:[ \t]*(\S+)",
+            ),
+        }
+    }
+}
+
+fn is_skipped(entry: &DirEntry) -> bool {
+    entry.file_type().is_dir()
+        && entry.depth() > 0
+        && entry
+            .file_name()
+            .to_str()
+            .is_some_and(|name| SKIPPED_DIRS.contains(&name))
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name()
+        .and_then(|s| s.to_str())
+        .unwrap_or_default()
+        .to_lowercase()
+}
+
+fn is_yaml(name: &str) -> bool {
+    name.ends_with(".yaml") || name.ends_with(".yml")
+}
+
+fn is_compose(name: &str) -> bool {
+    is_yaml(name) && (name.starts_with("docker-compose") || name.starts_with("compose"))
+}
+
+fn is_application_config(name: &str) -> bool {
+    name == ".env"
+        || name.starts_with(".env.")
+        || name.ends_with(".env")
+        || (name.starts_with("application") && (is_yaml(name) || name.ends_with(".properties")))
+}
+
+/// Whether the file configures something other than a developer's machine,
+/// e.g. `application-prod.yml` or `.env.test`.
+fn is_remote_profile(name: &str) -> bool {
+    ["test", "prod", "staging", "dev", "production"]
+        .iter()
+        .any(|profile| {
+            name.contains(&format!("-{}", profile)) || name.contains(&format!(".{}", profile))
+        })
+}
+
+fn is_postgres_image(line: &str) -> bool {
+    let line = line.trim_start().trim_start_matches("- ");
+    line.starts_with("image:") && line.contains("postgres")
+}
+
+fn placeholder(value: &str) -> bool {
+    value.contains("${") || value.contains("{{") || value.starts_with('<')
+}
+
+/// Picks up `key: value` / `KEY=value` settings that name a database user,
+/// password, database or schema.
+fn scan_key_values(patterns: &Patterns, contents: &str, db: &mut Database) {
+    for line in contents.lines() {
+        let caps = match patterns.key_value.captures(line) {
+            Some(caps) => caps,
+            None => continue,
+        };
+        let key = caps[1].to_lowercase().replace('-', "_");
+        let value = caps[2].to_owned();
+        if placeholder(&value) {
+            continue;
+        }
+
+        let slot = if key.ends_with("datasource.username")
+            || [
+                "username",
+                "postgres_user",
+                "pguser",
+                "db_user",
+                "db_username",
+                "database_user",
+            ]
+            .contains(&key.as_str())
+        {
+            &mut db.user
+        } else if key.ends_with("datasource.password")
+            || [
+                "password",
+                "postgres_password",
+                "pgpassword",
+                "db_password",
+                "db_pass",
+                "database_password",
+            ]
+            .contains(&key.as_str())
+        {
+            &mut db.password
+        } else if ["postgres_db", "pgdatabase", "db_name", "database_name"].contains(&key.as_str())
+        {
+            &mut db.database
+        } else if key.ends_with("flyway.schemas")
+            || key.ends_with("default_schema")
+            || ["db_schema", "database_schema"].contains(&key.as_str())
+        {
+            &mut db.schema
+        } else {
+            continue;
+        };
+
+        if slot.is_none() {
+            *slot = Some(value);
+        }
+    }
+}
+
+/// Picks up JDBC and `postgres://` connection URLs.
+fn scan_urls(patterns: &Patterns, contents: &str) -> Vec<Database> {
+    let jdbc = patterns
+        .jdbc_url
+        .captures_iter(contents)
+        .map(|caps| Database {
+            host: Some(caps[1].to_owned()),
+            port: caps.get(2).and_then(|p| p.as_str().parse().ok()),
+            database: Some(caps[3].to_owned()),
+            schema: caps.get(4).and_then(|params| {
+                params
+                    .as_str()
+                    .split('&')
+                    .find_map(|p| p.strip_prefix("currentSchema="))
+                    .map(ToOwned::to_owned)
+            }),
+            ..Database::default()
+        });
+    let urls = patterns
+        .postgres_url
+        .captures_iter(contents)
+        // the tail of a JDBC URL looks the same
+        .filter(|caps| !contents[..caps.get(0).map_or(0, |m| m.start())].ends_with("jdbc:"))
+        .map(|caps| Database {
+            user: caps.get(1).map(|u| u.as_str().to_owned()),
+            password: caps.get(2).map(|p| p.as_str().to_owned()),
+            host: Some(caps[3].to_owned()),
+            port: caps.get(4).and_then(|p| p.as_str().parse().ok()),
+            database: Some(caps[5].to_owned()),
+            ..Database::default()
+        });
+
+    jdbc.chain(urls)
+        .filter(|db| !db.host.as_deref().is_some_and(placeholder))
+        .collect()
+}
+
+/// Looks for Kubernetes Deployments running a Postgres image, the namespace
+/// the project deploys to and Cloud SQL instance connection names (e.g. in
+/// `cloud_sql_proxy` sidecars or Helm values).
+fn scan_manifests(patterns: &Patterns, contents: &str, findings: &mut Findings) -> bool {
+    let mut found = false;
+
+    for document in contents.split("\n---") {
+        let namespace = patterns
+            .metadata_namespace
+            .captures(document)
+            .map(|caps| caps[1].to_owned())
+            .filter(|ns| !placeholder(ns));
+        if findings.namespace.is_none() && namespace.is_some() {
+            findings.namespace = namespace.clone();
+            found = true;
+        }
+
+        let is_postgres_deployment =
+            document.contains("kind: Deployment") && document.lines().any(is_postgres_image);
+        if is_postgres_deployment && findings.deployment.is_none() {
+            if let Some(caps) = patterns.metadata_name.captures(document) {
+                findings.deployment = Some(Deployment {
+                    name: caps[1].to_owned(),
+                    namespace,
+                });
+                found = true;
+            }
+        }
+    }
+
+    if findings.cloudsql_instance.is_none() {
+        let instance = contents
+            .lines()
+            .filter(|l| {
+                let l = l.to_lowercase();
+                l.contains("instance") || l.contains("cloudsql") || l.contains("cloud_sql")
+            })
+            .find_map(|l| patterns.cloudsql_instance.captures(l))
+            .map(|caps| caps[1].to_owned());
+        if instance.is_some() {
+            findings.cloudsql_instance = instance;
+            found = true;
+        }
+    }
+
+    found
+}
+
+fn scan_compose(patterns: &Patterns, contents: &str, db: &mut Database) -> bool {
+    if !contents.lines().any(is_postgres_image) {
+        return false;
+    }
+
+    if db.port.is_none() {
+        db.port = patterns
+            .compose_port
+            .captures(contents)
+            .and_then(|caps| caps[1].parse().ok());
+    }
+    scan_key_values(patterns, contents, db);
+
+    true
+}
+
+/// Walks `dir` looking for Kubernetes manifests, Helm values, docker-compose
+/// files and application configuration that reveal how the project connects
+/// to Postgres.
+pub fn scan<P: AsRef<Path>>(dir: P) -> crate::Result<Findings> {
+    let patterns = Patterns::new();
+    let mut findings = Findings::default();
+
+    let entries = WalkDir::new(dir.as_ref())
+        .max_depth(MAX_DEPTH)
+        .sort_by_file_name()
+        .into_iter()
+        .filter_entry(|e| !is_skipped(e));
+
+    for entry in entries {
+        let entry = entry?;
+        if !entry.file_type().is_file() {
+            continue;
+        }
+        let path = entry.path();
+        let name = file_name(path);
+        if !(is_yaml(&name) || is_application_config(&name)) {
+            continue;
+        }
+        // not every file is UTF-8, those can't tell us anything anyway
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(_) => continue,
+        };
+
+        let mut found = false;
+
+        if is_compose(&name) {
+            found |= scan_compose(&patterns, &contents, &mut findings.local);
+        } else if is_yaml(&name) {
+            found |= scan_manifests(&patterns, &contents, &mut findings);
+        }
+
+        if is_application_config(&name) {
+            let mut db = Database::default();
+            scan_key_values(&patterns, &contents, &mut db);
+            for url in scan_urls(&patterns, &contents) {
+                db.merge(&url);
+            }
+            if !db.is_empty() || db.host.is_some() {
+                found = true;
+                if is_remote_profile(&name) || !db.is_local() {
+                    findings.remote.merge(&db);
+                } else {
+                    findings.local.merge(&db);
+                }
+            }
+        }
+
+        if found {
+            findings.sources.push(path.to_path_buf());
+        }
+    }
+
+    Ok(findings)
+}
+
+fn quote(value: &str) -> String {
+    Value::String(value.to_owned()).to_string()
+}
+
+/// Writes user, password, database and schema, taking anything `db` doesn't
+/// know from `fallback` (except the password, which differs per environment).
+fn render_credentials(out: &mut String, db: &Database, fallback: &Database) {
+    let user = db.user.as_deref().or(fallback.user.as_deref());
+    let database = db.database.as_deref().or(fallback.database.as_deref());
+    let schema = db.schema.as_deref().or(fallback.schema.as_deref());
+
+    out.push_str(&format!(
+        "user = {}\n",
+        quote(user.unwrap_or(PLACEHOLDER_USER))
+    ));
+    let password = db.password.as_deref().unwrap_or(PLACEHOLDER_PASSWORD);
+    out.push_str(&format!("password = {}\n", quote(password)));
+    out.push_str(&format!(
+        "database = {}\n",
+        quote(database.unwrap_or(PLACEHOLDER_DATABASE))
+    ));
+    if let Some(schema) = schema {
+        out.push_str(&format!("schema = {}\n", quote(schema)));
+    }
+}
+
+/// A generated configuration block, e.g. `[postgres.test]`.
+pub struct Block {
+    /// The dotted name of the block's table, e.g. `postgres.test`.
+    pub name: &'static str,
+    pub text: String,
+}
+
+/// Renders the findings as figcli configuration blocks. Values that couldn't
+/// be found are left as `<insert ...>` placeholders.
+pub fn blocks(findings: &Findings) -> Vec<Block> {
+    let mut blocks = vec![];
+
+    if !findings.local.is_empty() {
+        let local = &findings.local;
+        let mut text = String::from("[postgres.local]\ntype = \"direct\"\n");
+        if let Some(host) = local.host.as_deref().filter(|h| *h != "localhost") {
+            text.push_str(&format!("host = {}\n", quote(host)));
+        }
+        if let Some(port) = local.port.filter(|p| *p != 5432) {
+            text.push_str(&format!("port = {}\n", port));
+        }
+        render_credentials(&mut text, local, &Database::default());
+        blocks.push(Block {
+            name: "postgres.local",
+            text,
+        });
+    }
+
+    if let Some(deployment) = &findings.deployment {
+        let namespace = deployment
+            .namespace
+            .as_deref()
+            .or(findings.namespace.as_deref())
+            .unwrap_or("default");
+        let mut text = format!(
+            "[postgres.test]\ntype = {{ kubernetes = {{ context = {}, namespace = {}, deployment = {} }} }}\n",
+            quote(PLACEHOLDER_CONTEXT),
+            quote(namespace),
+            quote(&deployment.name)
+        );
+        render_credentials(&mut text, &findings.remote, &findings.local);
+        blocks.push(Block {
+            name: "postgres.test",
+            text,
+        });
+    }
+
+    if let Some(instance) = &findings.cloudsql_instance {
+        let mut text = format!(
+            "[postgres.prod]\ntype = {{ gcloudproxy = {{ instance = {} }} }}\n",
+            quote(instance)
+        );
+        render_credentials(&mut text, &findings.remote, &findings.local);
+        blocks.push(Block {
+            name: "postgres.prod",
+            text,
+        });
+    }
+
+    if let Some(namespace) = &findings.namespace {
+        blocks.push(Block {
+            name: "port_forward",
+            text: format!(
+                "[port_forward]\ncontext = {}\nnamespace = {}\n",
+                quote(PLACEHOLDER_CONTEXT),
+                quote(namespace)
+            ),
+        });
+    }
+
+    blocks
+}
+
+/// Renders `blocks` as a new configuration file.
+pub fn render(blocks: &[Block]) -> String {
+    let mut out = String::from("# fig-cli configuration\n");
+    out.push_str(
+        "# generated by `figcli config init`, replace any remaining <insert ...> values\n",
+    );
+    for block in blocks {
+        out.push('\n');
+        out.push_str(&block.text);
+    }
+    out
+}
+
+/// Adds the `blocks` that are missing from the existing configuration file
+/// `contents`. Everything already in the file, including blocks that were
+/// generated before and edited since, is kept as it is; the names of blocks
+/// that were skipped because they already exist (also as a legacy
+/// `[postgres_test]` style block) are returned alongside.
+pub fn merge<'a>(contents: &str, blocks: &'a [Block]) -> crate::Result<(String, Vec<&'a str>)> {
+    let existing: Value = toml::from_str(contents)?;
+    let mut merged = contents.to_owned();
+    let mut kept = vec![];
+
+    for block in blocks {
+        let exists = block
+            .name
+            .split('.')
+            .try_fold(&existing, |value, key| value.get(key))
+            .is_some();
+        if exists || existing.get(block.name.replace('.', "_")).is_some() {
+            kept.push(block.name);
+            continue;
+        }
+        if !merged.is_empty() && !merged.ends_with('\n') {
+            merged.push('\n');
+        }
+        if !merged.is_empty() {
+            merged.push('\n');
+        }
+        merged.push_str(&block.text);
+    }
+
+    Ok((merged, kept))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::env;
+
+    #[test]
+    fn key_values_fill_the_first_value_per_setting() {
+        let contents = "spring.datasource.username=app\n\
+                        spring.datasource.password: ${DB_PASSWORD}\n\
+                        PGPASSWORD=\"secret\" # local only\n\
+                        db-user: other\n\
+                        POSTGRES_DB: app_db\n\
+                        flyway.schemas=core\n";
+        let mut db = Database::default();
+        scan_key_values(&Patterns::new(), contents, &mut db);
+
+        assert_eq!(db.user.as_deref(), Some("app"));
+        assert_eq!(db.password.as_deref(), Some("secret"));
+        assert_eq!(db.database.as_deref(), Some("app_db"));
+        assert_eq!(db.schema.as_deref(), Some("core"));
+    }
+
+    #[test]
+    fn urls_yield_connection_details() {
+        let contents = "url: jdbc:postgresql://db.internal:5433/app?ssl=true&currentSchema=core\n\
+                        DATABASE_URL=postgres://app:pw@localhost/app_dev\n\
+                        other: postgresql://${DB_HOST}:5432/app\n";
+        let dbs = scan_urls(&Patterns::new(), contents);
+
+        assert_eq!(dbs.len(), 2);
+        assert_eq!(dbs[0].host.as_deref(), Some("db.internal"));
+        assert_eq!(dbs[0].port, Some(5433));
+        assert_eq!(dbs[0].database.as_deref(), Some("app"));
+        assert_eq!(dbs[0].schema.as_deref(), Some("core"));
+        assert_eq!(dbs[1].user.as_deref(), Some("app"));
+        assert_eq!(dbs[1].password.as_deref(), Some("pw"));
+        assert_eq!(dbs[1].host.as_deref(), Some("localhost"));
+        assert_eq!(dbs[1].port, None);
+        assert_eq!(dbs[1].database.as_deref(), Some("app_dev"));
+    }
+
+    #[test]
+    fn scan_finds_local_remote_and_cluster_settings() {
+        let dir = env::temp_dir().join(format!("figcli-scan-{}", crate::util::random_alphanum(8)));
+        let files = [
+            (
+                "docker-compose.yml",
+                "services:\n  db:\n    image: postgres:14\n    ports:\n      - \"5433:5432\"\n    \
+                 environment:\n      POSTGRES_USER: app\n      POSTGRES_PASSWORD: local-pw\n      \
+                 POSTGRES_DB: app\n",
+            ),
+            (
+                "src/main/resources/application-prod.yml",
+                "spring:\n  datasource:\n    \
+                 url: jdbc:postgresql://10.0.0.5:5432/app?currentSchema=core\n    username: app_prod\n",
+            ),
+            (
+                "k8s/deploy.yaml",
+                "kind: Deployment\nmetadata:\n  name: app-db\n  namespace: app-ns\nspec:\n  \
+                 template:\n    spec:\n      containers:\n        - image: postgres:14\n\
+                 ---\nkind: Deployment\nmetadata:\n  name: app\nspec:\n  template:\n    spec:\n      \
+                 containers:\n        - args:\n            - \"-instances=my-project:us-east1:app-db=tcp:5432\"\n",
+            ),
+            ("target/application.yml", "password: build-output\n"),
+        ];
+        for (name, contents) in files.iter() {
+            let path = dir.join(name);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, contents).unwrap();
+        }
+
+        let findings = scan(&dir);
+        fs::remove_dir_all(&dir).unwrap();
+        let findings = findings.unwrap();
+
+        assert_eq!(
+            findings.sources,
+            vec![
+                dir.join("docker-compose.yml"),
+                dir.join("k8s/deploy.yaml"),
+                dir.join("src/main/resources/application-prod.yml"),
+            ]
+        );
+        assert_eq!(findings.local.port, Some(5433));
+        assert_eq!(findings.local.user.as_deref(), Some("app"));
+        assert_eq!(findings.local.password.as_deref(), Some("local-pw"));
+        assert_eq!(findings.remote.host.as_deref(), Some("10.0.0.5"));
+        assert_eq!(findings.remote.user.as_deref(), Some("app_prod"));
+        assert_eq!(findings.remote.schema.as_deref(), Some("core"));
+        assert_eq!(findings.remote.password, None);
+        let deployment = findings.deployment.unwrap();
+        assert_eq!(deployment.name, "app-db");
+        assert_eq!(deployment.namespace.as_deref(), Some("app-ns"));
+        assert_eq!(findings.namespace.as_deref(), Some("app-ns"));
+        assert_eq!(
+            findings.cloudsql_instance.as_deref(),
+            Some("my-project:us-east1:app-db")
+        );
+    }
+
+    #[test]
+    fn blocks_fall_back_to_local_settings_except_the_password() {
+        let findings = Findings {
+            local: Database {
+                port: Some(5433),
+                user: Some("app".to_owned()),
+                password: Some("local-pw".to_owned()),
+                database: Some("app".to_owned()),
+                ..Database::default()
+            },
+            deployment: Some(Deployment {
+                name: "app-db".to_owned(),
+                namespace: None,
+            }),
+            namespace: Some("app-ns".to_owned()),
+            ..Findings::default()
+        };
+        let blocks = blocks(&findings);
+
+        let names = blocks.iter().map(|b| b.name).collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            vec!["postgres.local", "postgres.test", "port_forward"]
+        );
+        assert_eq!(
+            blocks[0].text,
+            "[postgres.local]\ntype = \"direct\"\nport = 5433\nuser = \"app\"\n\
+             password = \"local-pw\"\ndatabase = \"app\"\n"
+        );
+        assert!(blocks[1]
+            .text
+            .contains("namespace = \"app-ns\", deployment = \"app-db\""));
+        assert!(blocks[1].text.contains("user = \"app\"\n"));
+        assert!(blocks[1]
+            .text
+            .contains("password = \"<insert password>\"\n"));
+
+        // the rendered file is valid configuration
+        let rendered: Value = toml::from_str(&render(&blocks)).unwrap();
+        assert!(rendered["postgres"]["test"]["type"]["kubernetes"].is_table());
+    }
+
+    fn block(name: &'static str) -> Block {
+        Block {
+            name,
+            text: format!("[{}]\nuser = \"generated\"\n", name),
+        }
+    }
+
+    #[test]
+    fn merge_appends_missing_blocks() {
+        let blocks = vec![block("postgres.local"), block("postgres.test")];
+        let (merged, kept) = merge("[postgres.local]\nuser = \"mine\"", &blocks).unwrap();
+
+        assert_eq!(
+            merged,
+            "[postgres.local]\nuser = \"mine\"\n\n[postgres.test]\nuser = \"generated\"\n"
+        );
+        assert_eq!(kept, vec!["postgres.local"]);
+    }
+
+    #[test]
+    fn merge_keeps_legacy_blocks() {
+        let blocks = vec![block("postgres.test"), block("port_forward")];
+        let contents = "[postgres_test]\nuser = \"mine\"\n\n[port_forward]\nnamespace = \"ns\"\n";
+        let (merged, kept) = merge(contents, &blocks).unwrap();
+
+        assert_eq!(merged, contents);
+        assert_eq!(kept, vec!["postgres.test", "port_forward"]);
+    }
+
+    #[test]
+    fn merge_into_empty_file() {
+        let blocks = vec![block("postgres.local")];
+        let (merged, kept) = merge("", &blocks).unwrap();
+
+        assert_eq!(merged, blocks[0].text);
+        assert!(kept.is_empty());
+    }
+
+    #[test]
+    fn merge_rejects_invalid_config() {
+        assert!(merge("[postgres.local", &[block("postgres.local")]).is_err());
+    }
+}
@@ -0,0 +1,245 @@
+use hmac::{Hmac, Mac};
+use rand::{thread_rng, RngCore};
+use sha2::{Digest, Sha256};
+
+/// Iteration count Postgres uses for the secrets it generates.
+const ITERATIONS: u32 = 4096;
+
+type HmacSha256 = Hmac<Sha256>;
+
+fn hmac(key: &[u8], message: &[u8]) -> [u8; 32] {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(message);
+
+    mac.finalize().into_bytes().into()
+}
+
+fn sha256(bytes: &[u8]) -> [u8; 32] {
+    Sha256::digest(bytes).into()
+}
+
+/// `Hi()` from RFC 5802, i.e. PBKDF2 with HMAC-SHA-256.
+fn salted_password(password: &str, salt: &[u8], iterations: u32) -> [u8; 32] {
+    let mut salted = [0u8; 32];
+    pbkdf2::pbkdf2_hmac::<Sha256>(password.as_bytes(), salt, iterations, &mut salted);
+
+    salted
+}
+
+/// A SCRAM-SHA-256 secret for `password` in the format Postgres stores in
+/// `pg_authid` and pgbouncer reads from its `auth_file`:
+/// `SCRAM-SHA-256$<iterations>:<salt>$<StoredKey>:<ServerKey>`.
+pub fn secret(password: &str) -> String {
+    let mut salt = [0u8; 16];
+    thread_rng().fill_bytes(&mut salt);
+
+    salted_secret(password, &salt)
+}
+
+fn salted_secret(password: &str, salt: &[u8]) -> String {
+    let salted = salted_password(password, salt, ITERATIONS);
+    let stored_key = sha256(&hmac(&salted, b"Client Key"));
+    let server_key = hmac(&salted, b"Server Key");
+
+    format!(
+        "SCRAM-SHA-256${}:{}${}:{}",
+        ITERATIONS,
+        base64::encode(salt),
+        base64::encode(stored_key),
+        base64::encode(server_key)
+    )
+}
+
+/// The client side of a SCRAM-SHA-256 exchange (RFC 5802/7677) without
+/// channel binding, as Postgres does it: the user name is left empty, the
+/// server takes it from the startup message.
+pub struct Client {
+    password: String,
+    nonce: String,
+    client_first_bare: String,
+    server_signature: Option<[u8; 32]>,
+}
+
+impl Client {
+    pub fn new(password: &str) -> Self {
+        let nonce = crate::util::random_alphanum(24);
+
+        Client {
+            password: password.to_owned(),
+            client_first_bare: format!("n=,r={}", nonce),
+            nonce,
+            server_signature: None,
+        }
+    }
+
+    pub fn client_first(&self) -> String {
+        format!("n,,{}", self.client_first_bare)
+    }
+
+    /// Answers the server-first-message with the client proof.
+    pub fn client_final(&mut self, server_first: &str) -> Result<String, String> {
+        let attribute = |name: &str| {
+            server_first
+                .split(',')
+                .find_map(|part| part.strip_prefix(name))
+                .ok_or_else(|| format!("server-first-message has no {}", name))
+        };
+        let nonce = attribute("r=")?;
+        let salt = base64::decode(attribute("s=")?).map_err(|e| e.to_string())?;
+        let iterations = attribute("i=")?.parse::<u32>().map_err(|e| e.to_string())?;
+
+        if !nonce.starts_with(&self.nonce) {
+            return Err("server nonce doesn't extend the client nonce".to_owned());
+        }
+
+        let salted = salted_password(&self.password, &salt, iterations);
+        let client_key = hmac(&salted, b"Client Key");
+        let stored_key = sha256(&client_key);
+        // "biws" is base64 of the "n,," gs2 header
+        let without_proof = format!("c=biws,r={}", nonce);
+        let auth_message = format!(
+            "{},{},{}",
+            self.client_first_bare, server_first, without_proof
+        );
+
+        let client_signature = hmac(&stored_key, auth_message.as_bytes());
+        let proof = client_key
+            .iter()
+            .zip(client_signature.iter())
+            .map(|(key, signature)| key ^ signature)
+            .collect::<Vec<_>>();
+        let server_key = hmac(&salted, b"Server Key");
+        self.server_signature = Some(hmac(&server_key, auth_message.as_bytes()));
+
+        Ok(format!("{},p={}", without_proof, base64::encode(proof)))
+    }
+
+    /// Checks the server-final-message, proving the server knew the password
+    /// too.
+    pub fn verify(&self, server_final: &str) -> Result<(), String> {
+        let signature = server_final
+            .strip_prefix("v=")
+            .ok_or_else(|| format!("authentication failed: {}", server_final))?;
+        let signature = base64::decode(signature).map_err(|e| e.to_string())?;
+
+        match self.server_signature {
+            Some(expected) if expected[..] == signature[..] => Ok(()),
+            _ => Err("server signature doesn't match".to_owned()),
+        }
+    }
+}
+
+/// The server's side of the client-final-message: checks the client `proof`
+/// and returns the server-final-message, for tests of code speaking SCRAM.
+#[cfg(test)]
+pub fn server_final(
+    password: &str,
+    salt: &[u8],
+    auth_message: &str,
+    proof: &[u8],
+) -> Option<String> {
+    let salted = salted_password(password, salt, ITERATIONS);
+    let client_key = hmac(&salted, b"Client Key");
+    let signature = hmac(&sha256(&client_key), auth_message.as_bytes());
+    let expected = client_key
+        .iter()
+        .zip(signature.iter())
+        .map(|(key, signature)| key ^ signature)
+        .collect::<Vec<_>>();
+    let server_key = hmac(&salted, b"Server Key");
+
+    (expected == proof).then(|| {
+        format!(
+            "v={}",
+            base64::encode(hmac(&server_key, auth_message.as_bytes()))
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the SCRAM-SHA-256 example exchange from RFC 7677, section 3
+    const PASSWORD: &str = "pencil";
+    const CLIENT_NONCE: &str = "rOprNGfwEbeRWgbNEkqO";
+    const SALT: &str = "W22ZaJ0SNY7soEsUEjb6gQ==";
+    const SERVER_FIRST: &str =
+        "r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,s=W22ZaJ0SNY7soEsUEjb6gQ==,i=4096";
+    const CLIENT_FINAL: &str = "c=biws,r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,p=dHzbZapWIk4jUhN+Ute9ytag9zjfMHgsqmmiz7AndVQ=";
+    const SERVER_FINAL: &str = "v=6rriTRBi23WpRR/wtup+mMhUZUn/dB5nLTJRsjl95G4=";
+
+    /// The RFC's client, which sends its user name unlike Postgres clients.
+    fn rfc_client() -> Client {
+        Client {
+            password: PASSWORD.to_owned(),
+            nonce: CLIENT_NONCE.to_owned(),
+            client_first_bare: format!("n=user,r={}", CLIENT_NONCE),
+            server_signature: None,
+        }
+    }
+
+    #[test]
+    fn client_matches_rfc_7677() {
+        let mut client = rfc_client();
+
+        assert_eq!(client.client_first(), "n,,n=user,r=rOprNGfwEbeRWgbNEkqO");
+        assert_eq!(client.client_final(SERVER_FIRST).unwrap(), CLIENT_FINAL);
+        assert_eq!(client.verify(SERVER_FINAL), Ok(()));
+    }
+
+    #[test]
+    fn client_rejects_wrong_server_signature_and_nonce() {
+        let mut client = rfc_client();
+        client.client_final(SERVER_FIRST).unwrap();
+        assert!(client
+            .verify("v=AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=")
+            .is_err());
+        assert!(client.verify("e=invalid-proof").is_err());
+
+        let foreign_nonce = SERVER_FIRST.replace(CLIENT_NONCE, "someoneElsesNonce");
+        assert!(rfc_client().client_final(&foreign_nonce).is_err());
+    }
+
+    #[test]
+    fn postgres_client_sends_no_user_name() {
+        let client = Client::new(PASSWORD);
+
+        assert!(client.client_first().starts_with("n,,n=,r="));
+    }
+
+    #[test]
+    fn secret_verifies_rfc_7677_exchange() {
+        let secret = salted_secret(PASSWORD, &base64::decode(SALT).unwrap());
+        let (head, keys) = secret.rsplit_once('$').unwrap();
+        assert_eq!(head, format!("SCRAM-SHA-256$4096:{}", SALT));
+        let (stored_key, server_key) = keys.split_once(':').unwrap();
+        let stored_key = base64::decode(stored_key).unwrap();
+        let server_key = base64::decode(server_key).unwrap();
+
+        // what a server holding only the secret checks
+        let without_proof = CLIENT_FINAL.rsplit_once(",p=").unwrap().0;
+        let auth_message = format!(
+            "n=user,r={},{},{}",
+            CLIENT_NONCE, SERVER_FIRST, without_proof
+        );
+        let proof = base64::decode(CLIENT_FINAL.rsplit_once(",p=").unwrap().1).unwrap();
+        let client_key = hmac(&stored_key, auth_message.as_bytes())
+            .iter()
+            .zip(proof.iter())
+            .map(|(signature, proof)| signature ^ proof)
+            .collect::<Vec<_>>();
+        assert_eq!(sha256(&client_key)[..], stored_key[..]);
+
+        let server_signature = hmac(&server_key, auth_message.as_bytes());
+        assert_eq!(
+            format!("v={}", base64::encode(server_signature)),
+            SERVER_FINAL
+        );
+    }
+
+    #[test]
+    fn secrets_are_salted() {
+        assert_ne!(secret(PASSWORD), secret(PASSWORD));
+    }
+}
@@ -0,0 +1,109 @@
+use std::io;
+use std::mem;
+use std::ptr;
+use std::sync::atomic::{AtomicI32, Ordering};
+
+use libc::c_int;
+
+/// Signals that figcli intercepts while it is running child processes, so
+/// they can be forwarded and the children torn down instead of leaking.
+pub const FORWARDED: [c_int; 3] = [libc::SIGINT, libc::SIGTERM, libc::SIGHUP];
+
+static RECEIVED: AtomicI32 = AtomicI32::new(0);
+
+extern "C" fn record(sig: c_int) {
+    RECEIVED.store(sig, Ordering::SeqCst);
+}
+
+/// Replaces the default disposition of the `FORWARDED` signals for as long as
+/// it is alive, restoring the previous handlers on drop.
+pub struct Handlers {
+    previous: Vec<(c_int, libc::sigaction)>,
+}
+
+impl Handlers {
+    pub fn install() -> io::Result<Self> {
+        let mut previous = Vec::with_capacity(FORWARDED.len());
+
+        for &sig in FORWARDED.iter() {
+            // SAFETY: `record` only touches an atomic, which is async-signal-safe.
+            unsafe {
+                let mut action: libc::sigaction = mem::zeroed();
+                action.sa_sigaction = record as *const () as libc::sighandler_t;
+                action.sa_flags = libc::SA_RESTART;
+                libc::sigemptyset(&mut action.sa_mask);
+
+                let mut old: libc::sigaction = mem::zeroed();
+                if libc::sigaction(sig, &action, &mut old) != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                previous.push((sig, old));
+            }
+        }
+
+        RECEIVED.store(0, Ordering::SeqCst);
+
+        Ok(Handlers { previous })
+    }
+}
+
+impl Drop for Handlers {
+    fn drop(&mut self) {
+        for (sig, old) in self.previous.iter() {
+            // SAFETY: restores a disposition previously returned by sigaction.
+            unsafe {
+                libc::sigaction(*sig, old, ptr::null_mut());
+            }
+        }
+    }
+}
+
+/// Returns the most recently received signal, if any, clearing it.
+pub fn take() -> Option<c_int> {
+    match RECEIVED.swap(0, Ordering::SeqCst) {
+        0 => None,
+        sig => Some(sig),
+    }
+}
+
+/// Sends `sig` to `pid`, or to the whole process group led by `pid`.
+pub fn send(pid: u32, sig: c_int, group: bool) -> io::Result<()> {
+    let pid = pid as libc::pid_t;
+    // SAFETY: plain syscalls, failures are reported through errno.
+    let result = unsafe {
+        if group {
+            libc::killpg(pid, sig)
+        } else {
+            libc::kill(pid, sig)
+        }
+    };
+
+    if result == 0 {
+        Ok(())
+    } else {
+        let err = io::Error::last_os_error();
+        // the process (group) is already gone
+        if err.raw_os_error() == Some(libc::ESRCH) {
+            Ok(())
+        } else {
+            Err(err)
+        }
+    }
+}
+
+pub fn name(sig: c_int) -> &'static str {
+    match sig {
+        libc::SIGINT => "SIGINT",
+        libc::SIGTERM => "SIGTERM",
+        libc::SIGHUP => "SIGHUP",
+        libc::SIGKILL => "SIGKILL",
+        _ => "signal",
+    }
+}
+
+/// Whether stdin is attached to a terminal, i.e. the child may need to stay in
+/// the foreground process group to read from it.
+pub fn stdin_is_tty() -> bool {
+    // SAFETY: isatty has no preconditions.
+    unsafe { libc::isatty(libc::STDIN_FILENO) == 1 }
+}
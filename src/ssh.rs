@@ -0,0 +1,160 @@
+use std::path::Path;
+use std::process::Command;
+
+use crate::config::SshConfig;
+use crate::util;
+
+/// Options for every hop of the tunnel: it can't prompt for anything, so
+/// passwords and unknown host keys fail instead of hanging or being trusted
+/// on first use.
+const BATCH_OPTIONS: &[(&str, &str)] = &[("BatchMode", "yes"), ("StrictHostKeyChecking", "yes")];
+
+/// The `ssh -N -L` command forwarding `127.0.0.1:port` to `db_host:db_port`
+/// as the bastion of `config` sees it.
+pub fn tunnel_cmd(
+    config: &SshConfig,
+    port: u16,
+    db_host: &str,
+    db_port: u16,
+) -> crate::Result<Command> {
+    // `-J` runs a plain `ssh` for each jump host, which only takes over the
+    // config file: that file sets the batch options for them, and includes
+    // the user's and the system's configuration after them
+    let config_file = match &config.jump {
+        Some(_) => {
+            let path = util::temp_file("ssh_config");
+            util::write_private(&path, &jump_config())?;
+            Some(path)
+        }
+        None => None,
+    };
+
+    let mut cmd = Command::new("ssh");
+    cmd.args(tunnel_args(
+        config,
+        port,
+        db_host,
+        db_port,
+        config_file.as_deref(),
+    ));
+
+    Ok(cmd)
+}
+
+fn tunnel_args(
+    config: &SshConfig,
+    port: u16,
+    db_host: &str,
+    db_port: u16,
+    config_file: Option<&Path>,
+) -> Vec<String> {
+    let mut args = vec!["-N".to_owned()];
+    if let Some(config_file) = config_file {
+        args.extend(vec!["-F".to_owned(), config_file.display().to_string()]);
+    }
+    for (option, value) in BATCH_OPTIONS.iter().chain(&[
+        ("ExitOnForwardFailure", "yes"),
+        ("ServerAliveInterval", "15"),
+    ]) {
+        args.extend(vec!["-o".to_owned(), format!("{}={}", option, value)]);
+    }
+    args.extend(vec![
+        "-L".to_owned(),
+        format!("127.0.0.1:{}:{}:{}", port, util::url_host(db_host), db_port),
+    ]);
+    if let Some(ssh_port) = config.port {
+        args.extend(vec!["-p".to_owned(), ssh_port.to_string()]);
+    }
+    if let Some(user) = &config.user {
+        args.extend(vec!["-l".to_owned(), user.clone()]);
+    }
+    if let Some(identity_file) = &config.identity_file {
+        args.extend(vec![
+            "-i".to_owned(),
+            identity_file.clone(),
+            "-o".to_owned(),
+            "IdentitiesOnly=yes".to_owned(),
+        ]);
+    }
+    if let Some(jump) = &config.jump {
+        args.extend(vec!["-J".to_owned(), jump.clone()]);
+    }
+    // a host starting with `-` is not an option
+    args.extend(vec!["--".to_owned(), config.host.clone()]);
+
+    args
+}
+
+/// The `ssh_config` the tunnel runs with when it has jump hosts. The first
+/// value ssh finds for an option wins, so the batch options come first.
+fn jump_config() -> String {
+    let mut contents = String::from("Host *\n");
+    for (option, value) in BATCH_OPTIONS {
+        contents.push_str(&format!("  {} {}\n", option, value));
+    }
+    contents.push_str("  Include ~/.ssh/config\n  Include /etc/ssh/ssh_config\n");
+
+    contents
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bastion(jump: Option<&str>) -> SshConfig {
+        SshConfig {
+            host: "bastion.figure.com".to_owned(),
+            user: Some("deploy".to_owned()),
+            port: Some(2222),
+            identity_file: None,
+            jump: jump.map(str::to_owned),
+        }
+    }
+
+    #[test]
+    fn tunnel_ends_options_before_the_host() {
+        let args = tunnel_args(&bastion(None), 5433, "10.0.3.7", 5432, None);
+
+        assert_eq!(args[args.len() - 2..], ["--", "bastion.figure.com"]);
+        assert!(args.contains(&"127.0.0.1:5433:10.0.3.7:5432".to_owned()));
+        assert!(args.contains(&"BatchMode=yes".to_owned()));
+        assert!(args.contains(&"StrictHostKeyChecking=yes".to_owned()));
+        assert!(!args.contains(&"-J".to_owned()));
+        assert!(!args.contains(&"-F".to_owned()));
+    }
+
+    #[test]
+    fn tunnel_brackets_ipv6_databases() {
+        let args = tunnel_args(&bastion(None), 5433, "fd00::7", 5432, None);
+
+        assert!(args.contains(&"127.0.0.1:5433:[fd00::7]:5432".to_owned()));
+    }
+
+    #[test]
+    fn jump_hosts_run_with_the_batch_config() {
+        let config_file = Path::new("/tmp/figcli.ssh_config");
+        let args = tunnel_args(
+            &bastion(Some("ops@gate.figure.com")),
+            5433,
+            "10.0.3.7",
+            5432,
+            Some(config_file),
+        );
+
+        assert_eq!(args[1..3], ["-F", "/tmp/figcli.ssh_config"]);
+        let jump = args.iter().position(|arg| arg == "-J").unwrap();
+        assert_eq!(args[jump + 1], "ops@gate.figure.com");
+        assert!(jump < args.iter().position(|arg| arg == "--").unwrap());
+    }
+
+    #[test]
+    fn jump_config_sets_batch_options_before_includes() {
+        let contents = jump_config();
+        let batch = contents.find("BatchMode yes").unwrap();
+        let strict = contents.find("StrictHostKeyChecking yes").unwrap();
+        let include = contents.find("Include").unwrap();
+
+        assert!(contents.starts_with("Host *\n"));
+        assert!(batch < include && strict < include);
+    }
+}
@@ -1,8 +1,12 @@
-use std::env::temp_dir;
-use std::io;
+use std::env::{self, temp_dir};
+use std::fmt;
+use std::fs;
+use std::io::{self, Write};
 use std::iter;
-use std::net::UdpSocket;
+use std::net::{IpAddr, UdpSocket};
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt, PermissionsExt};
 use std::path::{Path, PathBuf};
+use std::sync::Mutex;
 
 use getch::Getch;
 use rand::distributions::Alphanumeric;
@@ -12,60 +16,205 @@ use uuid::Uuid;
 #[derive(Clone, Debug)]
 pub struct ForwardingInfo {
     pub local_port: u16,
-    pub remote_host: String,
+    pub remote: Remote,
     pub remote_port: u16,
 }
 
+/// Where a forwarding ends up.
+#[derive(Clone, Debug)]
+pub enum Remote {
+    /// A host reachable from the cluster, forwarded to through the relay pod.
+    Host(String),
+    /// A resource `kubectl port-forward` reaches by itself, e.g. `svc/redis`.
+    Resource(String),
+}
+
+impl fmt::Display for Remote {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Remote::Host(host) => write!(f, "{}", host),
+            Remote::Resource(resource) => write!(f, "{}", resource),
+        }
+    }
+}
+
 pub fn find_available_port() -> Result<u16, std::io::Error> {
     Ok(UdpSocket::bind("127.0.0.1:0")?.local_addr()?.port())
 }
 
+/// Where `PATH` has the executable `name`, like `which`.
+pub fn find_in_path(name: &str) -> Option<PathBuf> {
+    env::split_paths(&env::var_os("PATH")?)
+        .map(|dir| dir.join(name))
+        .find(|path| {
+            fs::metadata(path)
+                .is_ok_and(|meta| meta.is_file() && meta.permissions().mode() & 0o111 != 0)
+        })
+}
+
+/// Every path handed out by `temp_file` and `private_temp_dir`, removed again
+/// by `TempFileCleanup`.
+static TEMP_FILES: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
+
+fn register_temp_path(path: &Path) {
+    if let Ok(mut files) = TEMP_FILES.lock() {
+        files.push(path.to_path_buf());
+    }
+}
+
 pub fn temp_file(extension: &str) -> PathBuf {
     let mut dir = temp_dir();
     let file_name = format!("{}.{}", Uuid::new_v4(), extension);
 
     dir.push(file_name);
+    register_temp_path(&dir);
 
     dir
 }
 
-/// Parses a string of the "<local-port>:<remote-host>:<remote-port>" or
-/// "<remote-host>:<remote-port>"
+/// Creates a new directory in the system temp directory that only the
+/// current user can access, for files holding credentials. It is removed
+/// with everything in it by `TempFileCleanup`.
+pub fn private_temp_dir() -> io::Result<PathBuf> {
+    let mut dir = temp_dir();
+    dir.push(format!("figcli-{}", Uuid::new_v4()));
+
+    fs::DirBuilder::new().mode(0o700).create(&dir)?;
+    register_temp_path(&dir);
+
+    Ok(dir)
+}
+
+/// Writes `contents` to `path`, creating it readable by the current user only.
+pub fn write_private<P: AsRef<Path>>(path: P, contents: &str) -> io::Result<()> {
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)?;
+
+    file.write_all(contents.as_bytes())
+}
+
+/// Deletes all files and directories created through `temp_file` and
+/// `private_temp_dir` when dropped. Held for the lifetime of `main` so they
+/// are removed on every exit path, including errors, panics and interrupted
+/// child processes.
+pub struct TempFileCleanup;
+
+impl Drop for TempFileCleanup {
+    fn drop(&mut self) {
+        if let Ok(mut files) = TEMP_FILES.lock() {
+            for path in files.drain(..) {
+                let _ = if path.is_dir() {
+                    fs::remove_dir_all(path)
+                } else {
+                    fs::remove_file(path)
+                };
+            }
+        }
+    }
+}
+
+/// Parses a string of the "<local-port>:<remote>:<remote-port>" or
+/// "<remote>:<remote-port>" form, where the remote is a host reached through
+/// the relay pod or a `svc/<name>`, `pod/<name>` or `deploy/<name>` that
+/// is forwarded to directly.
 pub fn parse_forwarding_string(host: &str) -> Result<ForwardingInfo, io::Error> {
     let parts = host.split(':').collect::<Vec<&str>>();
-    if parts.len() == 3 {
-        let local_port = parts[0]
-            .parse::<u16>()
-            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
-        let remote_host = parts[1];
-        let remote_port = parts[2]
-            .parse::<u16>()
-            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
-        Ok(ForwardingInfo {
-            local_port,
-            remote_host: remote_host.to_owned(),
-            remote_port,
-        })
-    } else if parts.len() == 2 {
-        let remote_host = parts[0];
-        let remote_port = parts[1]
-            .parse::<u16>()
-            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
-        Ok(ForwardingInfo {
-            local_port: find_available_port()?, // pick a random port
-            remote_host: remote_host.to_owned(),
-            remote_port,
-        })
-    } else {
-        Err(io::Error::new(
-            io::ErrorKind::InvalidInput,
-            "expected \
+    let (local_port, remote, remote_port) = match parts.len() {
+        3 => (
+            parts[0]
+                .parse::<u16>()
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?,
+            parts[1],
+            parts[2],
+        ),
+        2 => (find_available_port()?, parts[0], parts[1]), // pick a random port
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "expected \
                            \"<local-port>:<remote-host>:<remote-port>\" \
                            or \"<remote-host>:<remote-port>\"",
-        ))
+            ))
+        }
+    };
+    let remote_port = remote_port
+        .parse::<u16>()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+
+    Ok(ForwardingInfo {
+        local_port,
+        remote: parse_remote(remote)?,
+        remote_port,
+    })
+}
+
+fn parse_remote(remote: &str) -> Result<Remote, io::Error> {
+    let (kind, name) = match remote.split_once('/') {
+        Some(resource) => resource,
+        None => return Ok(Remote::Host(remote.to_owned())),
+    };
+    if name.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{:?} has no name", remote),
+        ));
+    }
+    let kind = match kind {
+        "svc" | "service" => "svc",
+        "pod" => "pod",
+        "deploy" | "deployment" => "deploy",
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "unknown resource type {:?}, expected svc, pod or deploy",
+                    kind
+                ),
+            ))
+        }
+    };
+
+    Ok(Remote::Resource(format!("{}/{}", kind, name)))
+}
+
+/// Whether `listen_addr` only accepts connections from this host.
+pub fn is_loopback(listen_addr: &str) -> bool {
+    listen_addr == "localhost"
+        || listen_addr
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .parse::<IpAddr>()
+            .is_ok_and(|ip| ip.is_loopback())
+}
+
+/// The host local clients connect to for a server listening on
+/// `listen_addr`. The wildcard addresses can't be connected to, `localhost`
+/// is used for them instead.
+pub fn connect_host(listen_addr: &str) -> &str {
+    match listen_addr {
+        "0.0.0.0" | "::" | "[::]" | "*" => "localhost",
+        addr => addr,
+    }
+}
+
+/// `host` as it goes into a URL, IPv6 addresses in brackets.
+pub fn url_host(host: &str) -> String {
+    if host.contains(':') && !host.starts_with('[') {
+        format!("[{}]", host)
+    } else {
+        host.to_owned()
     }
 }
 
+/// Quotes `s` as a single word for `sh`.
+pub fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', r"'\''"))
+}
+
 /// From https://docs.rs/rand/0.8.4/rand/distributions/struct.Alphanumeric.html
 pub fn random_alphanum(len: usize) -> String {
     let mut rng = thread_rng();
@@ -77,6 +226,51 @@ pub fn random_alphanum(len: usize) -> String {
         .to_lowercase()
 }
 
+/// Asks a yes/no `question`, reading a single key press.
+pub fn prompt_yes_no(question: &str) -> bool {
+    println!("\n{} [y/n]?", question);
+    let ch = Getch::new().getch().unwrap_or(0) as char;
+    ch == 'y' || ch == 'Y'
+}
+
+/// A line based diff of `old` and `new` in unified style, i.e. every line
+/// prefixed with "+", "-" or " ".
+pub fn line_diff(old: &str, new: &str) -> Vec<String> {
+    let old = old.lines().collect::<Vec<_>>();
+    let new = new.lines().collect::<Vec<_>>();
+
+    // lcs[i][j] is the length of the longest common subsequence of old[i..]
+    // and new[j..]
+    let mut lcs = vec![vec![0usize; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            lcs[i][j] = if old[i] == new[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+
+    let mut diff = Vec::with_capacity(old.len().max(new.len()));
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && old[i] == new[j] {
+            diff.push(format!(" {}", old[i]));
+            i += 1;
+            j += 1;
+        } else if i < old.len() && (j == new.len() || lcs[i + 1][j] >= lcs[i][j + 1]) {
+            diff.push(format!("-{}", old[i]));
+            i += 1;
+        } else {
+            diff.push(format!("+{}", new[j]));
+            j += 1;
+        }
+    }
+
+    diff
+}
+
 pub fn prompt_on_write<P: AsRef<Path>>(path: P) -> bool {
     if path.as_ref().exists() {
         println!(
@@ -89,3 +283,89 @@ pub fn prompt_on_write<P: AsRef<Path>>(path: P) -> bool {
         true
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn forwarding_to_host_through_relay_pod() {
+        let forwarding = parse_forwarding_string("5433:db.internal:5432").unwrap();
+
+        assert_eq!(forwarding.local_port, 5433);
+        assert!(matches!(&forwarding.remote, Remote::Host(host) if host == "db.internal"));
+        assert_eq!(forwarding.remote_port, 5432);
+    }
+
+    #[test]
+    fn forwarding_to_resource_normalizes_its_kind() {
+        let forwarding = parse_forwarding_string("8080:service/api:80").unwrap();
+        assert!(matches!(&forwarding.remote, Remote::Resource(r) if r == "svc/api"));
+
+        let forwarding = parse_forwarding_string("deployment/api:80").unwrap();
+        assert!(matches!(&forwarding.remote, Remote::Resource(r) if r == "deploy/api"));
+        assert_ne!(forwarding.local_port, 0);
+    }
+
+    #[test]
+    fn forwarding_rejects_malformed_strings() {
+        for invalid in &[
+            "5432",
+            "a:b:c:d",
+            "x:db:5432",
+            "db:port",
+            "job/x:80",
+            "svc/:80",
+            "5433:pod/:5432",
+        ] {
+            assert!(parse_forwarding_string(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn loopback_addresses() {
+        for addr in &["127.0.0.1", "127.0.0.2", "::1", "[::1]", "localhost"] {
+            assert!(is_loopback(addr), "{}", addr);
+        }
+        for addr in &["0.0.0.0", "::", "10.0.0.1", "db.internal"] {
+            assert!(!is_loopback(addr), "{}", addr);
+        }
+    }
+
+    #[test]
+    fn wildcard_addresses_connect_to_localhost() {
+        assert_eq!(connect_host("0.0.0.0"), "localhost");
+        assert_eq!(connect_host("[::]"), "localhost");
+        assert_eq!(connect_host("10.0.0.1"), "10.0.0.1");
+    }
+
+    #[test]
+    fn url_host_brackets_ipv6() {
+        assert_eq!(url_host("::1"), "[::1]");
+        assert_eq!(url_host("[::1]"), "[::1]");
+        assert_eq!(url_host("127.0.0.1"), "127.0.0.1");
+    }
+
+    #[test]
+    fn line_diff_marks_added_and_removed_lines() {
+        let old = "[postgres.local]\nport = 5432\nuser = \"app\"\n";
+        let new = "[postgres.local]\nport = 5433\nuser = \"app\"\ndatabase = \"app\"\n";
+
+        assert_eq!(
+            line_diff(old, new),
+            vec![
+                " [postgres.local]",
+                "-port = 5432",
+                "+port = 5433",
+                " user = \"app\"",
+                "+database = \"app\"",
+            ]
+        );
+    }
+
+    #[test]
+    fn line_diff_of_new_file_only_adds() {
+        assert_eq!(line_diff("", "a\nb"), vec!["+a", "+b"]);
+        assert_eq!(line_diff("a\nb", "a\nb"), vec![" a", " b"]);
+    }
+}
@@ -0,0 +1,642 @@
+use std::env;
+use std::fs;
+use std::io::Write;
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::config::{Credentials, VaultSource};
+use crate::FigError;
+
+const DEFAULT_FIELD: &str = "password";
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+/// Leases are renewed (or replaced) once less than this much of them is left,
+/// or a third of their duration, whichever is longer.
+const MIN_REFRESH_MARGIN: Duration = Duration::from_secs(60);
+
+#[derive(Deserialize)]
+struct SecretResponse {
+    #[serde(default)]
+    lease_id: String,
+    #[serde(default)]
+    renewable: bool,
+    #[serde(default)]
+    lease_duration: u64,
+    #[serde(default)]
+    data: Option<Map<String, Value>>,
+}
+
+/// Credentials read from Vault along with the lease that keeps them valid.
+/// This is what gets cached on disk between sessions.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Lease {
+    pub lease_id: String,
+    pub renewable: bool,
+    /// Seconds since the epoch.
+    pub expires_at: u64,
+    pub duration: u64,
+    pub username: Option<String>,
+    pub password: String,
+}
+
+impl Lease {
+    fn remaining(&self) -> Duration {
+        Duration::from_secs(self.expires_at.saturating_sub(now()))
+    }
+
+    fn refresh_margin(&self) -> Duration {
+        Duration::from_secs(self.duration / 3).max(MIN_REFRESH_MARGIN)
+    }
+
+    /// Static (KV) secrets have no lease and never need refreshing.
+    fn is_leased(&self) -> bool {
+        self.duration > 0
+    }
+
+    fn needs_refresh(&self) -> bool {
+        self.is_leased() && self.remaining() <= self.refresh_margin()
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+fn vault_error<E: std::fmt::Display>(context: &str) -> impl FnOnce(E) -> FigError + '_ {
+    move |e| FigError::VaultError(format!("{}: {}", context, e))
+}
+
+/// A minimal client for Vault's HTTP API, configured the same way as the
+/// `vault` cli: `VAULT_ADDR` and `VAULT_TOKEN` (or `~/.vault-token`).
+#[derive(Clone)]
+struct Client {
+    address: String,
+    token: String,
+    agent: ureq::Agent,
+}
+
+impl Client {
+    fn new(address: Option<&str>) -> crate::Result<Self> {
+        let address = address
+            .map(ToOwned::to_owned)
+            .or_else(|| env::var("VAULT_ADDR").ok())
+            .ok_or_else(|| FigError::VaultError("VAULT_ADDR is not set".to_owned()))?;
+        let token = match env::var("VAULT_TOKEN") {
+            Ok(token) => token,
+            Err(_) => {
+                let path = dirs::home_dir()
+                    .map(|home| home.join(".vault-token"))
+                    .ok_or_else(|| FigError::VaultError("no home directory".to_owned()))?;
+                fs::read_to_string(&path).map_err(vault_error(
+                    "VAULT_TOKEN is not set and ~/.vault-token is unreadable",
+                ))?
+            }
+        };
+
+        Ok(Client {
+            address: address.trim_end_matches('/').to_owned(),
+            token: token.trim().to_owned(),
+            agent: ureq::AgentBuilder::new().timeout(REQUEST_TIMEOUT).build(),
+        })
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!("{}/v1/{}", self.address, path.trim_start_matches('/'))
+    }
+
+    fn read(&self, path: &str) -> crate::Result<SecretResponse> {
+        self.agent
+            .get(&self.url(path))
+            .set("X-Vault-Token", &self.token)
+            .call()
+            .map_err(vault_error(path))?
+            .into_json()
+            .map_err(vault_error(path))
+    }
+
+    fn renew(&self, lease_id: &str, increment: u64) -> crate::Result<SecretResponse> {
+        self.agent
+            .put(&self.url("sys/leases/renew"))
+            .set("X-Vault-Token", &self.token)
+            .send_json(serde_json::json!({
+                "lease_id": lease_id,
+                "increment": increment,
+            }))
+            .map_err(vault_error("sys/leases/renew"))?
+            .into_json()
+            .map_err(vault_error("sys/leases/renew"))
+    }
+}
+
+/// Vault credentials for one `password_source`, cached on disk until their
+/// lease runs out.
+pub struct Session {
+    client: Client,
+    source: VaultSource,
+    cache_path: PathBuf,
+    lease: Lease,
+}
+
+impl Session {
+    /// Reuses cached credentials while their lease has enough time left,
+    /// renews the lease when it is about to expire, and reads new
+    /// credentials otherwise.
+    pub fn open<P: AsRef<Path>>(source: &VaultSource, cache_dir: P) -> crate::Result<Self> {
+        let client = Client::new(source.address.as_deref())?;
+        Session::open_with(client, source, cache_dir.as_ref())
+    }
+
+    fn open_with(client: Client, source: &VaultSource, cache_dir: &Path) -> crate::Result<Self> {
+        let cache_path = cache_dir.join(cache_key(&client.address, source));
+
+        let cached = fs::read_to_string(&cache_path)
+            .ok()
+            .and_then(|contents| serde_json::from_str::<Lease>(&contents).ok())
+            .filter(|lease| lease.remaining() > Duration::from_secs(0));
+
+        let mut session = match cached {
+            Some(lease) => Session {
+                client,
+                source: source.clone(),
+                cache_path,
+                lease,
+            },
+            None => {
+                let lease = read_lease(&client, source)?;
+                Session {
+                    client,
+                    source: source.clone(),
+                    cache_path,
+                    lease,
+                }
+            }
+        };
+
+        if session.lease.needs_refresh() {
+            session.refresh()?;
+        }
+        if session.lease.is_leased() {
+            session.save()?;
+        }
+
+        Ok(session)
+    }
+
+    pub fn credentials(&self, default_user: &str) -> Credentials {
+        Credentials {
+            user: self
+                .lease
+                .username
+                .clone()
+                .unwrap_or_else(|| default_user.to_owned()),
+            password: Some(self.lease.password.clone()),
+        }
+    }
+
+    /// Extends the lease, falling back to new credentials when it can't be
+    /// extended (far enough). Returns whether the credentials changed.
+    fn refresh(&mut self) -> crate::Result<bool> {
+        if self.lease.renewable && !self.lease.lease_id.is_empty() {
+            let renewed = self.client.renew(&self.lease.lease_id, self.lease.duration);
+            if let Ok(renewed) = renewed {
+                // a lease at its max TTL is "renewed" for less than asked for
+                if Duration::from_secs(renewed.lease_duration) > self.lease.refresh_margin() {
+                    self.lease.expires_at = now() + renewed.lease_duration;
+                    self.lease.renewable = renewed.renewable;
+                    return Ok(false);
+                }
+            }
+        }
+
+        self.lease = read_lease(&self.client, &self.source)?;
+        Ok(true)
+    }
+
+    fn save(&self) -> crate::Result<()> {
+        if let Some(dir) = self.cache_path.parent() {
+            fs::DirBuilder::new()
+                .recursive(true)
+                .mode(0o700)
+                .create(dir)?;
+        }
+        let contents =
+            serde_json::to_string(&self.lease).map_err(vault_error("could not serialize lease"))?;
+
+        let mut file = fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(&self.cache_path)?;
+        // `mode` only applies to new files
+        file.set_permissions(fs::Permissions::from_mode(0o600))?;
+        file.write_all(contents.as_bytes())?;
+
+        Ok(())
+    }
+
+    /// Keeps the credentials valid for as long as figcli runs, renewing the
+    /// lease before it expires. When new credentials had to be read,
+    /// `on_refresh` is called with them.
+    pub fn keep_alive<F>(mut self, default_user: String, on_refresh: F)
+    where
+        F: Fn(&Credentials) + Send + 'static,
+    {
+        if !self.lease.is_leased() {
+            return;
+        }
+
+        thread::spawn(move || loop {
+            let wait = self
+                .lease
+                .remaining()
+                .saturating_sub(self.lease.refresh_margin());
+            thread::sleep(wait.max(MIN_REFRESH_MARGIN / 2));
+
+            match self.refresh() {
+                Ok(changed) => {
+                    let _ = self.save();
+                    if changed {
+                        on_refresh(&self.credentials(&default_user));
+                    }
+                }
+                Err(e) => eprintln!("Could not refresh Vault credentials: {}", e),
+            }
+        });
+    }
+}
+
+fn read_lease(client: &Client, source: &VaultSource) -> crate::Result<Lease> {
+    let path = match &source.role {
+        Some(role) => format!("{}/creds/{}", source.path.trim_end_matches('/'), role),
+        None => source.path.clone(),
+    };
+    let response = client.read(&path)?;
+    let mut data = response
+        .data
+        .ok_or_else(|| FigError::VaultError(format!("{} returned no data", path)))?;
+
+    // KV version 2 nests the secret in another `data` object
+    if let (Some(Value::Object(inner)), true) = (data.get("data"), data.contains_key("metadata")) {
+        data = inner.clone();
+    }
+
+    let field = |name: &str| {
+        data.get(name)
+            .and_then(Value::as_str)
+            .map(ToOwned::to_owned)
+    };
+    let field_name = source.field.as_deref().unwrap_or(DEFAULT_FIELD);
+    let password = field(field_name)
+        .ok_or_else(|| FigError::VaultError(format!("{} has no field {}", path, field_name)))?;
+
+    Ok(Lease {
+        lease_id: response.lease_id,
+        renewable: response.renewable,
+        expires_at: now() + response.lease_duration,
+        duration: response.lease_duration,
+        // dynamic database credentials come with their own user
+        username: source.role.as_ref().and_then(|_| field("username")),
+        password,
+    })
+}
+
+fn cache_key(address: &str, source: &VaultSource) -> String {
+    let key = format!(
+        "{}_{}_{}",
+        address,
+        source.path,
+        source.role.as_deref().unwrap_or_default()
+    );
+
+    key.chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect::<String>()
+        + ".json"
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::io::{BufRead, BufReader, Read};
+    use std::net::TcpListener;
+    use std::sync::{Arc, Mutex};
+
+    /// A Vault server answering every request with what `respond` returns
+    /// for its method and path. The requests it got are kept in `requests`,
+    /// as `<method> <path> <body>`.
+    struct Stub {
+        client: Client,
+        requests: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl Stub {
+        fn start<F>(respond: F) -> Self
+        where
+            F: Fn(&str, &str) -> Value + Send + 'static,
+        {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let address = format!("http://{}", listener.local_addr().unwrap());
+            let requests = Arc::new(Mutex::new(vec![]));
+
+            let recorded = Arc::clone(&requests);
+            thread::spawn(move || {
+                for stream in listener.incoming() {
+                    let mut stream = stream.unwrap();
+                    let mut reader = BufReader::new(stream.try_clone().unwrap());
+                    let mut request_line = String::new();
+                    reader.read_line(&mut request_line).unwrap();
+                    let mut content_length = 0;
+                    let mut token = String::new();
+                    loop {
+                        let mut header = String::new();
+                        reader.read_line(&mut header).unwrap();
+                        let header = header.trim_end();
+                        if header.is_empty() {
+                            break;
+                        }
+                        let (name, value) = header.split_once(": ").unwrap();
+                        match name.to_lowercase().as_str() {
+                            "content-length" => content_length = value.parse().unwrap(),
+                            "x-vault-token" => token = value.to_owned(),
+                            _ => {}
+                        }
+                    }
+                    let mut body = vec![0; content_length];
+                    reader.read_exact(&mut body).unwrap();
+                    assert_eq!(token, "s.token");
+
+                    let mut words = request_line.split_whitespace();
+                    let (method, path) = (words.next().unwrap(), words.next().unwrap());
+                    let response = respond(method, path).to_string();
+                    recorded.lock().unwrap().push(format!(
+                        "{} {} {}",
+                        method,
+                        path,
+                        String::from_utf8_lossy(&body)
+                    ));
+                    write!(
+                        stream,
+                        "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\n\
+                         Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+                        response.len(),
+                        response
+                    )
+                    .unwrap();
+                }
+            });
+
+            Stub {
+                client: Client {
+                    address,
+                    token: "s.token".to_owned(),
+                    agent: ureq::AgentBuilder::new().timeout(REQUEST_TIMEOUT).build(),
+                },
+                requests,
+            }
+        }
+
+        fn requests(&self) -> Vec<String> {
+            self.requests.lock().unwrap().clone()
+        }
+    }
+
+    fn kv(path: &str) -> VaultSource {
+        VaultSource {
+            path: path.to_owned(),
+            field: None,
+            role: None,
+            address: None,
+        }
+    }
+
+    fn database(role: &str) -> VaultSource {
+        VaultSource {
+            role: Some(role.to_owned()),
+            ..kv("database")
+        }
+    }
+
+    /// `database/creds/ro` handing out `password`, and renewals for
+    /// `renewed_for` seconds.
+    fn creds(password: &'static str, renewed_for: u64) -> impl Fn(&str, &str) -> Value {
+        move |method, path| match (method, path) {
+            ("GET", "/v1/database/creds/ro") => json!({
+                "lease_id": "database/creds/ro/abc",
+                "renewable": true,
+                "lease_duration": 3600,
+                "data": { "username": "v-ro-1", "password": password },
+            }),
+            ("PUT", "/v1/sys/leases/renew") => json!({
+                "lease_id": "database/creds/ro/abc",
+                "renewable": true,
+                "lease_duration": renewed_for,
+            }),
+            _ => panic!("unexpected request {} {}", method, path),
+        }
+    }
+
+    fn cache_dir() -> PathBuf {
+        env::temp_dir().join(format!("figcli-vault-{}", crate::util::random_alphanum(8)))
+    }
+
+    fn lease(password: &str, remaining: u64) -> Lease {
+        Lease {
+            lease_id: "database/creds/ro/abc".to_owned(),
+            renewable: true,
+            expires_at: now() + remaining,
+            duration: 3600,
+            username: Some("v-ro-0".to_owned()),
+            password: password.to_owned(),
+        }
+    }
+
+    fn write_cache(dir: &Path, client: &Client, source: &VaultSource, lease: &Lease) {
+        fs::create_dir_all(dir).unwrap();
+        fs::write(
+            dir.join(cache_key(&client.address, source)),
+            serde_json::to_string(lease).unwrap(),
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn read_lease_uses_kv_v1_data() {
+        let stub = Stub::start(|_, path| {
+            assert_eq!(path, "/v1/secret/app");
+            json!({ "data": { "password": "kv1-pw", "username": "ignored" } })
+        });
+        let lease = read_lease(&stub.client, &kv("secret/app")).unwrap();
+
+        assert_eq!(lease.password, "kv1-pw");
+        assert_eq!(lease.username, None);
+        assert!(!lease.is_leased());
+    }
+
+    #[test]
+    fn read_lease_unwraps_kv_v2_data() {
+        let stub = Stub::start(|_, _| {
+            json!({
+                "data": {
+                    "data": { "password": "kv2-pw", "api_key": "key" },
+                    "metadata": { "version": 3 },
+                },
+            })
+        });
+
+        let lease = read_lease(&stub.client, &kv("secret/data/app")).unwrap();
+        assert_eq!(lease.password, "kv2-pw");
+
+        let source = VaultSource {
+            field: Some("api_key".to_owned()),
+            ..kv("secret/data/app")
+        };
+        assert_eq!(read_lease(&stub.client, &source).unwrap().password, "key");
+
+        let source = VaultSource {
+            field: Some("missing".to_owned()),
+            ..kv("secret/data/app")
+        };
+        assert!(read_lease(&stub.client, &source).is_err());
+    }
+
+    #[test]
+    fn read_lease_takes_user_and_lease_of_database_creds() {
+        let stub = Stub::start(creds("dyn-pw", 3600));
+        let lease = read_lease(&stub.client, &database("ro")).unwrap();
+
+        assert_eq!(lease.username.as_deref(), Some("v-ro-1"));
+        assert_eq!(lease.password, "dyn-pw");
+        assert_eq!(lease.lease_id, "database/creds/ro/abc");
+        assert!(lease.renewable && lease.is_leased());
+    }
+
+    #[test]
+    fn refresh_renews_the_lease() {
+        let stub = Stub::start(creds("new-pw", 3600));
+        let mut session = Session {
+            client: stub.client.clone(),
+            source: database("ro"),
+            cache_path: cache_dir().join("lease.json"),
+            lease: lease("old-pw", 30),
+        };
+
+        assert!(!session.refresh().unwrap());
+        assert_eq!(session.lease.password, "old-pw");
+        assert!(session.lease.remaining() > Duration::from_secs(3500));
+        let requests = stub.requests();
+        assert_eq!(requests.len(), 1);
+        assert!(requests[0].starts_with("PUT /v1/sys/leases/renew "));
+        assert!(requests[0].contains(r#""lease_id":"database/creds/ro/abc""#));
+    }
+
+    #[test]
+    fn refresh_reads_new_credentials_at_max_ttl() {
+        let stub = Stub::start(creds("new-pw", 30));
+        let mut session = Session {
+            client: stub.client.clone(),
+            source: database("ro"),
+            cache_path: cache_dir().join("lease.json"),
+            lease: lease("old-pw", 30),
+        };
+
+        assert!(session.refresh().unwrap());
+        assert_eq!(session.lease.password, "new-pw");
+        assert_eq!(session.lease.username.as_deref(), Some("v-ro-1"));
+        assert_eq!(stub.requests().len(), 2);
+    }
+
+    #[test]
+    fn refresh_reads_new_credentials_for_unrenewable_lease() {
+        let stub = Stub::start(creds("new-pw", 3600));
+        let mut session = Session {
+            client: stub.client.clone(),
+            source: database("ro"),
+            cache_path: cache_dir().join("lease.json"),
+            lease: Lease {
+                renewable: false,
+                ..lease("old-pw", 30)
+            },
+        };
+
+        assert!(session.refresh().unwrap());
+        assert_eq!(stub.requests().len(), 1);
+        assert!(stub.requests()[0].starts_with("GET /v1/database/creds/ro "));
+    }
+
+    #[test]
+    fn open_reuses_the_cached_lease() {
+        let stub = Stub::start(creds("new-pw", 3600));
+        let dir = cache_dir();
+        write_cache(
+            &dir,
+            &stub.client,
+            &database("ro"),
+            &lease("cached-pw", 3000),
+        );
+
+        let session = Session::open_with(stub.client.clone(), &database("ro"), &dir);
+        fs::remove_dir_all(&dir).unwrap();
+
+        let credentials = session.unwrap().credentials("app");
+        assert_eq!(credentials.user, "v-ro-0");
+        assert_eq!(credentials.password.as_deref(), Some("cached-pw"));
+        assert!(stub.requests().is_empty());
+    }
+
+    #[test]
+    fn open_renews_a_cached_lease_about_to_expire() {
+        let stub = Stub::start(creds("new-pw", 3600));
+        let dir = cache_dir();
+        write_cache(&dir, &stub.client, &database("ro"), &lease("cached-pw", 30));
+
+        let session = Session::open_with(stub.client.clone(), &database("ro"), &dir).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(session.lease.password, "cached-pw");
+        assert!(session.lease.remaining() > Duration::from_secs(3500));
+        assert_eq!(stub.requests().len(), 1);
+    }
+
+    #[test]
+    fn open_replaces_an_expired_lease_and_caches_it_privately() {
+        let stub = Stub::start(creds("new-pw", 3600));
+        let dir = cache_dir();
+        let expired = Lease {
+            expires_at: now() - 10,
+            ..lease("expired-pw", 0)
+        };
+        write_cache(&dir, &stub.client, &database("ro"), &expired);
+
+        let session = Session::open_with(stub.client.clone(), &database("ro"), &dir).unwrap();
+        let cache_path = dir.join(cache_key(&stub.client.address, &database("ro")));
+        let mode = fs::metadata(&cache_path).unwrap().permissions().mode();
+        let cached: Lease =
+            serde_json::from_str(&fs::read_to_string(&cache_path).unwrap()).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(session.lease.password, "new-pw");
+        assert_eq!(cached.password, "new-pw");
+        assert_eq!(mode & 0o777, 0o600);
+        assert_eq!(stub.requests().len(), 1);
+    }
+
+    #[test]
+    fn open_does_not_cache_static_secrets() {
+        let stub = Stub::start(|_, _| json!({ "data": { "password": "kv1-pw" } }));
+        let dir = cache_dir();
+
+        let session = Session::open_with(stub.client.clone(), &kv("secret/app"), &dir).unwrap();
+
+        assert_eq!(session.lease.password, "kv1-pw");
+        assert!(!dir.exists());
+    }
+}